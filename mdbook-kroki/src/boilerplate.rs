@@ -0,0 +1,72 @@
+//! mdbook预处理器的通用命令行流程，与主crate的`boilerplate`模块行为一致：
+//! `supports`子命令通过退出码（0/1）应答，mdbook版本不匹配时打印警告，错误按原因链逐行输出。
+//!
+//! 本crate独立构建（有自己的Cargo.lock），因此复制而不是依赖主crate。
+
+use anyhow::Result;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
+use semver::{Version, VersionReq};
+use std::{io, process};
+
+/// 处理 `supports` 子命令，否则按mdbook协议从stdin读取书籍、运行预处理器并输出到stdout
+pub fn run(preprocessor: impl Preprocessor, description: &str) {
+    let matches = App::new(preprocessor.name())
+        .about(description)
+        .subcommand(
+            SubCommand::with_name("supports")
+                .arg(Arg::with_name("renderer").required(true))
+                .about("Check whether a renderer is supported by this preprocessor"),
+        )
+        .get_matches();
+
+    if let Some(sub_args) = matches.subcommand_matches("supports") {
+        handle_supports(preprocessor, sub_args);
+    } else if let Err(e) = handle_preprocessing(preprocessor) {
+        print_error(&e);
+        process::exit(1);
+    }
+}
+
+fn handle_preprocessing(pre: impl Preprocessor) -> Result<()> {
+    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())?;
+
+    let book_version = Version::parse(&ctx.mdbook_version)?;
+    let version_req = VersionReq::parse(mdbook::MDBOOK_VERSION)?;
+
+    if !version_req.matches(&book_version) {
+        eprintln!(
+            "Warning: The {} plugin was built against version {} of mdbook, \
+             but we're being called from version {}",
+            pre.name(),
+            mdbook::MDBOOK_VERSION,
+            ctx.mdbook_version
+        );
+    }
+
+    let processed_book = pre.run(&ctx, book)?;
+    let out = serde_json::to_string(&processed_book)?;
+    println!("{}", out);
+
+    Ok(())
+}
+
+/// 通过退出码告诉mdbook是否支持该渲染器：支持为0，否则为1
+fn handle_supports(pre: impl Preprocessor, sub_args: &ArgMatches) -> ! {
+    let renderer = sub_args.value_of("renderer").expect("Required argument");
+    if pre.supports_renderer(renderer) {
+        process::exit(0);
+    } else {
+        process::exit(1);
+    }
+}
+
+/// 第一行是错误本身，之后每个原因一行，以 `  - ` 开头，最后空一行
+fn print_error(error: &anyhow::Error) {
+    let mut chain = error.chain();
+    eprintln!("{}", chain.next().unwrap());
+    for e in chain {
+        eprintln!("  - {e}");
+    }
+    eprintln!();
+}
@@ -0,0 +1,70 @@
+//! `[preprocessor.kroki]` 配置
+
+use anyhow::{bail, Context, Result};
+use mdbook::Config;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// 预处理器配置，也是渲染选项
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct KrokiConfig {
+    /// Kroki服务地址
+    pub endpoint: String,
+    /// 输出格式：svg内联，png/jpeg作为data URI图片
+    pub format: String,
+    /// 单个请求的超时时间（秒）
+    pub timeout: u64,
+    /// 同时渲染的最大数量（线程数）
+    pub workers: usize,
+    /// 每个请求附带的HTTP头
+    pub headers: BTreeMap<String, String>,
+    /// 有图表渲染失败时让预处理器失败（非零退出），而不只是显示错误框
+    pub fail_on_error: bool,
+}
+
+impl Default for KrokiConfig {
+    fn default() -> Self {
+        KrokiConfig {
+            endpoint: "https://kroki.io".to_string(),
+            format: "svg".to_string(),
+            timeout: 30,
+            workers: 4,
+            headers: BTreeMap::new(),
+            fail_on_error: false,
+        }
+    }
+}
+
+/// 支持的输出格式
+const FORMATS: &[&str] = &["svg", "png", "jpeg"];
+
+impl KrokiConfig {
+    /// 从book.toml读取名为`name`的预处理器配置，未配置时使用默认值
+    pub fn load(config: &Config, name: &str) -> Result<Self> {
+        let kroki_config: KrokiConfig = config
+            .get_deserialized_opt(format!("preprocessor.{name}"))
+            .with_context(|| format!("invalid [preprocessor.{name}] configuration"))?
+            .unwrap_or_default();
+        if !FORMATS.contains(&kroki_config.format.as_str()) {
+            bail!("unsupported format {:?}, expected one of {FORMATS:?}", kroki_config.format);
+        }
+        if kroki_config.workers == 0 {
+            bail!("workers must be at least 1");
+        }
+        // HTTP头名不区分大小写，同名的头在请求里会互相覆盖
+        let mut names = BTreeMap::new();
+        for name in kroki_config.headers.keys() {
+            if let Some(other) = names.insert(name.to_ascii_lowercase(), name) {
+                bail!("headers {other:?} and {name:?} are the same header; names are case-insensitive");
+            }
+        }
+        Ok(kroki_config)
+    }
+
+    /// 请求超时
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
@@ -1,105 +1,74 @@
-use anyhow::{Result};
+mod boilerplate;
+mod config;
+mod render;
+#[cfg(test)]
+mod test;
+
+use anyhow::{bail, Result};
+use config::KrokiConfig;
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
 use rayon::prelude::*;
-use regex::Regex;
-use std::borrow::Cow;
+use render::{render_kroki_blocks, KrokiRenderer};
 
-// 定义数据结构表示文本片段
-enum Segment<'a> {
-    Text(&'a str),
-    CodeBlock { diagram_type: String, code: String }
+fn main() {
+    boilerplate::run(
+        KrokiPreprocessor,
+        "An mdbook preprocessor that renders kroki diagrams in fenced code blocks",
+    );
 }
 
-pub fn render_kroki_blocks(input: &str) -> Result<String> {
-    let re = Regex::new(r"(?s)```kroki-(\w+)\n(.*?)```").unwrap();
-    let mut segments = Vec::new();
-    let mut last_end = 0;
-
-    // 第一步：分割文本为交替的普通文本和代码块
-    for cap in re.captures_iter(input) {
-        let full_match = cap.get(0).unwrap();
-        let text_segment = &input[last_end..full_match.start()];
-        if !text_segment.is_empty() {
-            segments.push(Segment::Text(text_segment));
-        }
+/// Kroki预处理器
+pub struct KrokiPreprocessor;
 
-        let diagram_type = cap[1].to_lowercase();
-        let code = cap[2].trim().to_string();
-        segments.push(Segment::CodeBlock { diagram_type, code });
-        last_end = full_match.end();
+impl Preprocessor for KrokiPreprocessor {
+    fn name(&self) -> &str {
+        "kroki"
     }
 
-    // 添加最后的文本片段
-    let remaining_text = &input[last_end..];
-    if !remaining_text.is_empty() {
-        segments.push(Segment::Text(remaining_text));
-    }
+    /// 用rayon在有界线程池中并行渲染所有章节，最后汇总所有渲染失败的图表
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
+        let config = KrokiConfig::load(&ctx.config, self.name())?;
+        let fail_on_error = config.fail_on_error;
+        let renderer = KrokiRenderer::new(config)?;
+
+        let mut chapters = Vec::new();
+        collect_chapters(&mut book.sections, &mut chapters);
+        let failures = renderer.install(|| {
+            chapters
+                .into_par_iter()
+                .map(|(name, content)| -> Result<Vec<String>> {
+                    let (rendered, failures) = render_kroki_blocks(content, &renderer)?;
+                    *content = rendered;
+                    Ok(failures.iter().map(|f| format!("chapter \"{name}\": {f}")).collect())
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
 
-    // 第二步：并行处理代码块
-    let processed: Vec<Cow<str>> = segments.into_par_iter().map(|seg| {
-        match seg {
-            Segment::Text(t) => Cow::Borrowed(t),
-            Segment::CodeBlock { diagram_type, code } => {
-                // 每个线程使用独立的Client
-                let client = reqwest::blocking::Client::new();
-                let url = format!("https://kroki.io/{}/svg", diagram_type);
-                
-                match client.post(&url)
-                    .body(code)
-                    .header("Content-Type", "text/plain")
-                    .send()
-                    .and_then(|r| r.error_for_status())
-                    .and_then(|r| r.text()) 
-                {
-                    Ok(svg) => Cow::Owned(svg),
-                    Err(e) => {
-                        eprintln!("diagram render failed ({}): {}", diagram_type, e);
-                        Cow::Owned(format!("<!-- diagram render failed: {} -->", diagram_type))
-                    }
-                }
+        let failures = failures.concat();
+        if !failures.is_empty() {
+            let summary = format!("{} diagrams failed to render:\n  {}", failures.len(), failures.join("\n  "));
+            if fail_on_error {
+                bail!(summary);
             }
+            eprintln!("Warning: {summary}");
         }
-    }).collect();
 
-    // 第三步：拼接最终结果
-    Ok(processed.into_iter().collect())
-}
-
-/// 使用示例
-fn main() -> anyhow::Result<()> {
+        Ok(book)
+    }
 
-    let code = r#"
-```kroki-graphviz
-digraph G {
-a -> b [dir=both color="red:blue"]
-c -> d [dir=none color="green:red;0.25:blue"]
+    /// 输出的是内联SVG，只支持html渲染器
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        renderer == "html"
+    }
 }
-```
 
-## kroki-wavedrom
-
-```kroki-wavedrom
-{ signal: [
-{ name: "clk",         wave: "p.....|..." },
-{ name: "Data",        wave: "x.345x|=.x", data: ["head", "body", "tail", "data"] },
-{ name: "Request",     wave: "0.1..0|1.0" },
-{},
-{ name: "Acknowledge", wave: "1.....|01." }
-]}
-```
-## test1
-
-```markdown
-test1
-```
-
-## test2
-
-```bob
-    test2
-```
-    "#;
-
-    let result = render_kroki_blocks(code)?;
-    println!("{}", result);
-    Ok(())
-}
\ No newline at end of file
+/// 递归收集所有章节（包括子章节）的名称和内容的可变引用
+fn collect_chapters<'a>(items: &'a mut [BookItem], chapters: &mut Vec<(&'a str, &'a mut String)>) {
+    for item in items {
+        if let BookItem::Chapter(Chapter { name, content, sub_items, .. }) = item {
+            chapters.push((name, content));
+            collect_chapters(sub_items, chapters);
+        }
+    }
+}
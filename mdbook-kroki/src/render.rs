@@ -0,0 +1,239 @@
+use crate::config::KrokiConfig;
+use anyhow::{Context, Result};
+use base64::Engine;
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::StatusCode;
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+
+/// 一个渲染失败的图表
+#[derive(Debug)]
+pub struct RenderFailure {
+    /// 图表类型
+    pub diagram_type: String,
+    /// Kroki返回的HTTP状态；连接失败、超时等没有状态
+    pub status: Option<StatusCode>,
+    /// Kroki返回的错误内容，或请求本身的错误
+    pub message: String,
+}
+
+impl fmt::Display for RenderFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} diagram", self.diagram_type)?;
+        if let Some(status) = self.status {
+            write!(f, " (HTTP {status})")?;
+        }
+        // 摘要中只显示第一行，完整内容在错误框里
+        write!(f, ": {}", self.message.lines().next().unwrap_or_default())
+    }
+}
+
+/// 整个构建共用的渲染器：一个HTTP客户端（连接池）和一个有界线程池
+pub struct KrokiRenderer {
+    config: KrokiConfig,
+    client: Client,
+    pool: ThreadPool,
+}
+
+impl KrokiRenderer {
+    /// 按配置创建HTTP客户端和线程池
+    pub fn new(config: KrokiConfig) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::try_from(name.as_str())
+                .with_context(|| format!("invalid header name {name:?}"))?;
+            let value = HeaderValue::try_from(value.as_str())
+                .with_context(|| format!("invalid value for header {name}"))?;
+            headers.insert(name, value);
+        }
+        let client = Client::builder()
+            .timeout(config.timeout())
+            .default_headers(headers)
+            .pool_max_idle_per_host(config.workers)
+            .build()
+            .context("could not build http client")?;
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(config.workers)
+            .build()
+            .context("could not build thread pool")?;
+        Ok(KrokiRenderer { config, client, pool })
+    }
+
+    /// 在有界线程池中执行，其中的rayon并行操作（章节和代码块）都不会超过`workers`个线程
+    pub fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        self.pool.install(op)
+    }
+
+    /// 请求Kroki渲染一个图表，返回要内联的html
+    fn render_diagram(&self, diagram_type: &str, code: String) -> Result<String, RenderFailure> {
+        let failure = |status, message| RenderFailure {
+            diagram_type: diagram_type.to_string(),
+            status,
+            message,
+        };
+        // 请求错误的Display不含底层原因，用anyhow的`{:#}`带上整条错误链
+        let request_failure = |e: reqwest::Error| failure(e.status(), format!("{:#}", anyhow::Error::from(e)));
+
+        let format = &self.config.format;
+        let url = format!("{}/{diagram_type}/{format}", self.config.endpoint.trim_end_matches('/'));
+        let response = self
+            .client
+            .post(&url)
+            .body(code)
+            .header(CONTENT_TYPE, "text/plain")
+            .send()
+            .map_err(request_failure)?;
+        let status = response.status();
+        if !status.is_success() {
+            // Kroki在响应体中说明语法错误等原因
+            let body = response.text().unwrap_or_default();
+            return Err(failure(Some(status), body.trim().to_string()));
+        }
+        if format == "svg" {
+            return response.text().map_err(request_failure);
+        }
+        let bytes = response.bytes().map_err(request_failure)?;
+        let data = base64::engine::general_purpose::STANDARD.encode(bytes);
+        Ok(format!("<img src=\"data:image/{format};base64,{data}\" alt=\"{diagram_type} diagram\">"))
+    }
+}
+
+// 定义数据结构表示文本片段
+enum Segment<'a> {
+    Text(&'a str),
+    CodeBlock {
+        diagram_type: String,
+        code: String,
+        // 代码块在列表或引用中时，续行需要的前缀
+        indent: String,
+    },
+}
+
+/// 将文本中所有 ```kroki-<类型> 代码块替换为渲染后的图表
+///
+/// 渲染失败的代码块替换为可见的错误框，失败信息一并返回。
+/// 应在[KrokiRenderer::install]中调用，以限制并发请求数
+pub fn render_kroki_blocks(
+    input: &str,
+    renderer: &KrokiRenderer,
+) -> Result<(String, Vec<RenderFailure>)> {
+    let mut segments = Vec::new();
+    let mut last_end = 0;
+
+    // 第一步：用markdown解析器找出真正的围栏代码块，其余文本原样保留
+    for (range, diagram_type, code) in find_kroki_blocks(input) {
+        let text_segment = &input[last_end..range.start];
+        if !text_segment.is_empty() {
+            segments.push(Segment::Text(text_segment));
+        }
+
+        let indent = continuation_indent(input, range.start);
+        segments.push(Segment::CodeBlock { diagram_type, code, indent });
+        last_end = range.end;
+    }
+
+    // 添加最后的文本片段
+    let remaining_text = &input[last_end..];
+    if !remaining_text.is_empty() {
+        segments.push(Segment::Text(remaining_text));
+    }
+
+    // 第二步：并行处理代码块
+    let processed: Vec<(Cow<str>, Option<RenderFailure>)> = segments.into_par_iter().map(|seg| {
+        match seg {
+            Segment::Text(t) => (Cow::Borrowed(t), None),
+            Segment::CodeBlock { diagram_type, code, indent } => {
+                let (html, failure) = match renderer.render_diagram(&diagram_type, code) {
+                    Ok(html) => (html, None),
+                    Err(failure) => (error_box(&failure), Some(failure)),
+                };
+                (Cow::Owned(html.trim_end().replace('\n', &format!("\n{indent}"))), failure)
+            }
+        }
+    }).collect();
+
+    // 第三步：拼接最终结果
+    let mut output = String::with_capacity(input.len());
+    let mut failures = Vec::new();
+    for (html, failure) in processed {
+        output.push_str(&html);
+        failures.extend(failure);
+    }
+    Ok((output, failures))
+}
+
+/// 替代渲染失败的图表的错误框
+///
+/// 不含空行，否则markdown会在空行处结束这个html块
+pub(crate) fn error_box(failure: &RenderFailure) -> String {
+    let status = failure.status.map(|status| format!(" (HTTP {status})")).unwrap_or_default();
+    let message = escape_html(&failure.message).replace('\n', "&#10;");
+    format!(
+        "<div class=\"kroki-error\" style=\"border: 1px solid #d33; border-radius: 4px; \
+         padding: 0.5em 1em; color: #d33; background: #fff5f5;\">\n\
+         <strong>Failed to render {} diagram{status}</strong>\n\
+         <pre style=\"white-space: pre-wrap; margin: 0.5em 0 0;\">{message}</pre>\n\
+         </div>",
+        escape_html(&failure.diagram_type)
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// 找出所有语言为 `kroki-<类型>` 的围栏代码块：(整个代码块的范围, 图表类型, 源码)
+pub(crate) fn find_kroki_blocks(input: &str) -> Vec<(Range<usize>, String, String)> {
+    let mut blocks = Vec::new();
+    let mut current: Option<(usize, String, String)> = None;
+
+    for (event, range) in Parser::new_ext(input, Options::all()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                // 信息字符串的第一个词是语言，其余是属性
+                let lang = info.split_whitespace().next().unwrap_or_default();
+                if let Some(diagram_type) = lang.strip_prefix("kroki-").filter(|t| !t.is_empty()) {
+                    current = Some((range.start, diagram_type.to_lowercase(), String::new()));
+                }
+            }
+            // 解析器已去掉围栏和容器前缀（列表缩进、`>`）
+            Event::Text(text) => {
+                if let Some((_, _, code)) = &mut current {
+                    code.push_str(&text);
+                }
+            }
+            Event::End(Tag::CodeBlock(_)) => {
+                if let Some((start, diagram_type, code)) = current.take() {
+                    // 只去掉结尾换行：ditaa、svgbob等图表的开头空白是图的一部分
+                    let code = code.replace("\r\n", "\n");
+                    let code = code.strip_suffix('\n').unwrap_or(&code).to_string();
+                    blocks.push((start..range.end, diagram_type, code));
+                }
+            }
+            _ => {}
+        }
+    }
+    blocks
+}
+
+/// 代码块所在行首的容器前缀（缩进、`>`、列表标记）对应的续行前缀
+pub(crate) fn continuation_indent(input: &str, start: usize) -> String {
+    let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+    let prefix = &input[line_start..start];
+    if !prefix.chars().all(|c| " \t>-*+.)".contains(c) || c.is_ascii_digit()) {
+        return String::new();
+    }
+    // 引用标记和制表符保留，列表标记换成等宽空格
+    prefix
+        .chars()
+        .map(|c| if c == '>' || c == '\t' { c } else { ' ' })
+        .collect()
+}
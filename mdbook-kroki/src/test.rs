@@ -0,0 +1,121 @@
+use crate::config::KrokiConfig;
+use crate::render::{continuation_indent, error_box, find_kroki_blocks, render_kroki_blocks};
+use crate::render::{KrokiRenderer, RenderFailure};
+use pretty_assertions::assert_eq;
+use reqwest::StatusCode;
+
+fn blocks(input: &str) -> Vec<(String, String, String)> {
+    find_kroki_blocks(input)
+        .into_iter()
+        .map(|(range, diagram_type, code)| (input[range].to_string(), diagram_type, code))
+        .collect()
+}
+
+#[test]
+fn finds_only_real_fences() {
+    let input = "\
+````markdown
+```kroki-dot
+not a diagram
+```
+````
+
+<!--
+```kroki-dot
+commented out
+```
+-->
+
+~~~kroki-c4-plantuml title=\"Context\"
+Person(user, \"User\")
+~~~
+";
+    assert_eq!(
+        blocks(input),
+        vec![(
+            "~~~kroki-c4-plantuml title=\"Context\"\nPerson(user, \"User\")\n~~~".to_string(),
+            "c4-plantuml".to_string(),
+            "Person(user, \"User\")".to_string()
+        )]
+    );
+}
+
+#[test]
+fn handles_crlf_and_long_fences() {
+    let input = "text\r\n\r\n````kroki-Mermaid\r\ngraph TD\r\n  A --> B\r\n````\r\n";
+    let found = blocks(input);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].1, "mermaid");
+    assert_eq!(found[0].2, "graph TD\n  A --> B");
+}
+
+#[test]
+fn keeps_leading_whitespace_of_diagrams() {
+    let input = "```kroki-svgbob\n\n    .---.\n    | A |\n    '---'\n```\n";
+    let found = blocks(input);
+    assert_eq!(found[0].2, "\n    .---.\n    | A |\n    '---'");
+}
+
+#[test]
+fn nested_fences_keep_their_container() {
+    let input = "- item\n\n  ```kroki-dot\n  digraph {}\n  ```\n\n> ```kroki-dot\n> digraph {}\n> ```\n";
+    let found = find_kroki_blocks(input);
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].2, "digraph {}");
+    assert_eq!(continuation_indent(input, found[0].0.start), "  ");
+    assert_eq!(continuation_indent(input, found[1].0.start), "> ");
+    assert_eq!(continuation_indent("see ```kroki-dot", 4), "");
+}
+
+#[test]
+fn rejects_headers_configured_twice() {
+    let config: mdbook::Config = r#"
+[preprocessor.kroki.headers]
+X-Token = "a"
+x-token = "b"
+"#
+    .parse()
+    .unwrap();
+    let error = KrokiConfig::load(&config, "kroki").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "headers \"X-Token\" and \"x-token\" are the same header; names are case-insensitive"
+    );
+
+    let config: mdbook::Config = "[preprocessor.kroki.headers]\nX-Token = \"a\"\nX-Team = \"b\"\n"
+        .parse()
+        .unwrap();
+    assert_eq!(KrokiConfig::load(&config, "kroki").unwrap().headers.len(), 2);
+}
+
+#[test]
+fn error_box_stays_one_html_block() {
+    let failure = RenderFailure {
+        diagram_type: "mermaid".to_string(),
+        status: Some(StatusCode::BAD_REQUEST),
+        message: "Parse error on line 2:\n\n<graph> TD".to_string(),
+    };
+    let html = error_box(&failure);
+    assert!(html.contains("Failed to render mermaid diagram (HTTP 400 Bad Request)"));
+    assert!(html.contains("Parse error on line 2:&#10;&#10;&lt;graph&gt; TD"));
+    assert!(!html.contains("\n\n"));
+    assert_eq!(failure.to_string(), "mermaid diagram (HTTP 400 Bad Request): Parse error on line 2:");
+}
+
+#[test]
+fn failed_renders_are_reported_and_shown() {
+    // 没有服务监听的端口，连接立即失败
+    let config = KrokiConfig {
+        endpoint: "http://127.0.0.1:9".to_string(),
+        ..KrokiConfig::default()
+    };
+    let renderer = KrokiRenderer::new(config).unwrap();
+    let input = "# A\n\n> ```kroki-dot\n> digraph {}\n> ```\n\nafter\n";
+    let (output, failures) = renderer.install(|| render_kroki_blocks(input, &renderer)).unwrap();
+
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].diagram_type, "dot");
+    assert_eq!(failures[0].status, None);
+    assert!(output.starts_with("# A\n\n> <div class=\"kroki-error\""));
+    assert!(output.contains("\n> </div>\n\nafter\n"));
+}
@@ -6,6 +6,9 @@
 //! This boilerplate has a few heavy dependencies (like serde_json and mdbook). If you want a small executable,
 //! you'll have to implement this functionality yourself.
 //!
+//! The library also carries [md_kroki], which finds and renders the diagrams in a markdown string;
+//! tools that only need to locate diagrams can use its [extract][md_kroki::MdKroki::extract] API.
+//!
 //! # Example
 //!
 //! The following is functionally identical to the [No-Op Preprocessor Example](https://github.com/rust-lang/mdBook/blob/master/examples/nop-preprocessor.rs)
@@ -17,7 +20,7 @@
 //! use anyhow::{bail, Result};
 //!
 //! fn main() {
-//!     mdbook_preprocessor_boilerplate::run(
+//!     boilerplate::run(
 //!         NoOpPreprocessor,
 //!         "An mdbook preprocessor that does nothing" // CLI description
 //!     );
@@ -49,6 +52,10 @@
 //! }
 //! ```
 
+pub mod md_kroki;
+#[cfg(test)]
+mod temp_dir;
+
 use anyhow::Result;
 use clap::{App, Arg, ArgMatches, SubCommand};
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
@@ -57,6 +64,22 @@ use std::{io, process};
 
 /// Checks renderer support and runs the preprocessor.
 pub fn run(preprocessor: impl Preprocessor, description: &str) {
+    run_with_subcommands(preprocessor, description, vec![], |name, _| {
+        unreachable!("no handler for subcommand {name}")
+    });
+}
+
+/// Like [run], but also accepts extra subcommands.
+///
+/// When one of `subcommands` is invoked, `handler` is called with its name and arguments
+/// instead of running the preprocessor. An error returned by the handler is printed and the
+/// process exits with status 1.
+pub fn run_with_subcommands<'a, 'b>(
+    preprocessor: impl Preprocessor,
+    description: &str,
+    subcommands: Vec<App<'a, 'b>>,
+    handler: impl FnOnce(&str, &ArgMatches) -> Result<()>,
+) {
     let matches = App::new(preprocessor.name())
         .about(description)
         .subcommand(
@@ -64,13 +87,23 @@ pub fn run(preprocessor: impl Preprocessor, description: &str) {
                 .arg(Arg::with_name("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommands(subcommands)
         .get_matches();
 
-    if let Some(sub_args) = matches.subcommand_matches("supports") {
-        handle_supports(preprocessor, sub_args);
-    } else if let Err(e) = handle_preprocessing(preprocessor) {
-        print_error(&e);
-        process::exit(1);
+    match matches.subcommand() {
+        ("supports", Some(sub_args)) => handle_supports(preprocessor, sub_args),
+        (name, Some(sub_args)) => {
+            if let Err(e) = handler(name, sub_args) {
+                print_error(&e);
+                process::exit(1);
+            }
+        }
+        _ => {
+            if let Err(e) = handle_preprocessing(preprocessor) {
+                print_error(&e);
+                process::exit(1);
+            }
+        }
     }
 }
 
@@ -0,0 +1,65 @@
+//! The `cache` subcommand: moves the render cache between machines, e.g. into an air-gapped
+//! environment that builds with `offline = true`.
+
+use crate::config::KrokiConfig;
+use crate::KrokiPreprocessor;
+use anyhow::Result;
+use boilerplate::md_kroki::RenderCache;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use mdbook::preprocess::Preprocessor;
+use mdbook::MDBook;
+use std::path::Path;
+
+/// Arguments of the `cache` subcommand.
+pub fn subcommand() -> App<'static, 'static> {
+    let archive = Arg::with_name("archive")
+        .required(true)
+        .help("Path of the tar archive");
+    let book_dir = Arg::with_name("book-dir")
+        .long("book-dir")
+        .takes_value(true)
+        .default_value(".")
+        .help("Root directory of the book (the one containing book.toml)");
+
+    SubCommand::with_name("cache")
+        .about("Export or import the render cache")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write every cached render into an archive")
+                .arg(archive.clone())
+                .arg(book_dir.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Add the renders in an archive to the cache")
+                .arg(archive)
+                .arg(book_dir),
+        )
+}
+
+/// Runs the `cache` subcommand.
+pub fn handle(args: &ArgMatches) -> Result<()> {
+    match args.subcommand() {
+        ("export", Some(args)) => {
+            let (cache, archive) = cache_and_archive(args)?;
+            let count = cache.export(archive)?;
+            eprintln!("exported {count} renders to {}", archive.display());
+        }
+        ("import", Some(args)) => {
+            let (cache, archive) = cache_and_archive(args)?;
+            let count = cache.import(archive)?;
+            eprintln!("imported {count} renders into {}", cache.dir().display());
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+    Ok(())
+}
+
+fn cache_and_archive<'a>(args: &'a ArgMatches) -> Result<(RenderCache, &'a Path)> {
+    let book = MDBook::load(args.value_of("book-dir").expect("has default"))?;
+    let config = KrokiConfig::load(&book.config, KrokiPreprocessor.name())?;
+    let cache = RenderCache::new(config.cache_dir(&book.root));
+    let archive = Path::new(args.value_of("archive").expect("required"));
+    Ok((cache, archive))
+}
@@ -0,0 +1,232 @@
+//! The `check` subcommand: renders every diagram in a book and reports failures without
+//! writing any output.
+
+use crate::config::KrokiConfig;
+use crate::KrokiPreprocessor;
+use anyhow::{bail, Result};
+use boilerplate::md_kroki::SourceLine;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use mdbook::book::BookItem;
+use mdbook::preprocess::Preprocessor;
+use mdbook::MDBook;
+use serde::Serialize;
+use serde_json::json;
+use std::path::{Component, Path, PathBuf};
+
+/// Arguments of the `check` subcommand.
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("check")
+        .about("Render every diagram in a book and report the ones that fail")
+        .arg(
+            Arg::with_name("book-dir")
+                .help("Root directory of the book (the one containing book.toml)")
+                .default_value("."),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json", "sarif"])
+                .default_value("text")
+                .help("Output format of the report"),
+        )
+}
+
+/// Runs the `check` subcommand, failing if any diagram could not be rendered.
+pub fn handle(args: &ArgMatches) -> Result<()> {
+    let book_dir = PathBuf::from(args.value_of("book-dir").expect("has default"));
+    let report = check_book(&book_dir)?;
+
+    let output = match args.value_of("format").expect("has default") {
+        "json" => serde_json::to_string_pretty(&report)?,
+        "sarif" => serde_json::to_string_pretty(&report.to_sarif())?,
+        _ => report.to_text(),
+    };
+    println!("{output}");
+
+    if !report.failures.is_empty() {
+        bail!(
+            "{} of {} diagrams failed to render",
+            report.failures.len(),
+            report.diagrams
+        );
+    }
+    Ok(())
+}
+
+/// Outcome of checking a whole book.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct Report {
+    pub(crate) diagrams: usize,
+    pub(crate) failures: Vec<Failure>,
+}
+
+/// A diagram that could not be extracted or rendered.
+#[derive(Serialize, Debug)]
+pub(crate) struct Failure {
+    pub(crate) file: PathBuf,
+    pub(crate) line: Option<usize>,
+    pub(crate) diagram_type: Option<String>,
+    pub(crate) message: String,
+}
+
+fn check_book(book_dir: &Path) -> Result<Report> {
+    let book = MDBook::load(book_dir)?;
+    let config = KrokiConfig::load(&book.config, KrokiPreprocessor.name())?;
+    let renderer = config.renderer(book.root.clone(), book.config.book.src.clone())?;
+
+    let chapters = book.book.iter().filter_map(|item| match item {
+        BookItem::Chapter(chapter) => Some(chapter),
+        _ => None,
+    });
+
+    let chapter_futures = chapters.map(|chapter| {
+        let file = match &chapter.source_path {
+            Some(path) => report_path(&book_dir.join(&book.config.book.src).join(path)),
+            None => PathBuf::from(&chapter.name),
+        };
+        let renderer = renderer.for_document(chapter.source_path.clone());
+
+        async move {
+            let mut report = Report::default();
+            // A malformed diagram is reported on its own; the rest of the chapter is still checked.
+            let mut specs = Vec::new();
+            for spec in renderer.extract_each(&chapter.content) {
+                match spec {
+                    Ok(spec) => specs.push(spec),
+                    Err(e) => {
+                        let line = e.downcast_ref::<SourceLine>().map(|l| l.0);
+                        // The line is reported separately, so drop it from the message.
+                        let message = e
+                            .chain()
+                            .skip(usize::from(line.is_some()))
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(": ");
+                        report.diagrams += 1;
+                        report.failures.push(Failure {
+                            file: file.clone(),
+                            line,
+                            diagram_type: None,
+                            message,
+                        });
+                    }
+                }
+            }
+
+            let results = futures::future::join_all(specs.into_iter().map(|spec| async {
+                let result = renderer.render_spec(&spec).await;
+                (spec, result)
+            }))
+            .await;
+
+            for (spec, result) in results {
+                report.diagrams += 1;
+                if let Err(e) = result {
+                    report.failures.push(Failure {
+                        file: file.clone(),
+                        line: Some(*spec.lines.start()),
+                        diagram_type: Some(spec.diagram_type),
+                        message: format!("{e:#}"),
+                    });
+                }
+            }
+            report.failures.sort_by_key(|failure| failure.line);
+            report
+        }
+    });
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let chapter_reports = rt.block_on(futures::future::join_all(chapter_futures));
+
+    Ok(chapter_reports
+        .into_iter()
+        .fold(Report::default(), |mut total, report| {
+            total.diagrams += report.diagrams;
+            total.failures.extend(report.failures);
+            total
+        }))
+}
+
+/// `path` relative to the working directory, the base that CI tools resolve report paths against.
+pub(crate) fn report_path(path: &Path) -> PathBuf {
+    let relative = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok())
+        .unwrap_or(path);
+    relative
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+impl Report {
+    pub(crate) fn to_text(&self) -> String {
+        let mut text = String::new();
+        for failure in &self.failures {
+            text.push_str(&failure.file.display().to_string());
+            if let Some(line) = failure.line {
+                text.push_str(&format!(":{line}"));
+            }
+            if let Some(diagram_type) = &failure.diagram_type {
+                text.push_str(&format!(": {diagram_type}"));
+            }
+            text.push_str(&format!(": {}\n", failure.message));
+        }
+        text.push_str(&format!(
+            "checked {} diagrams, {} failed",
+            self.diagrams,
+            self.failures.len()
+        ));
+        text
+    }
+
+    /// Static Analysis Results Interchange Format, understood by most CI annotation tools.
+    pub(crate) fn to_sarif(&self) -> serde_json::Value {
+        let results = self
+            .failures
+            .iter()
+            .map(|failure| {
+                let mut location = json!({
+                    "physicalLocation": {
+                        "artifactLocation": {
+                            "uri": failure.file.to_string_lossy().replace('\\', "/")
+                        }
+                    }
+                });
+                if let Some(line) = failure.line {
+                    location["physicalLocation"]["region"] = json!({ "startLine": line });
+                }
+                json!({
+                    "ruleId": "kroki-render",
+                    "level": "error",
+                    "message": { "text": match &failure.diagram_type {
+                        Some(diagram_type) => format!("{diagram_type} diagram failed to render: {}", failure.message),
+                        None => failure.message.clone(),
+                    }},
+                    "locations": [location],
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": KrokiPreprocessor.name(),
+                        "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                        "rules": [{
+                            "id": "kroki-render",
+                            "shortDescription": { "text": "Diagram failed to render" }
+                        }]
+                    }
+                },
+                "results": results,
+            }]
+        })
+    }
+}
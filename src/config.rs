@@ -0,0 +1,313 @@
+//! Preprocessor settings read from the `[preprocessor.kroki-preprocessor]` table in `book.toml`.
+
+use crate::generate::{GeneratorConfig, Generators};
+use crate::paths::PathPolicy;
+use anyhow::{anyhow, bail, Context, Result};
+use boilerplate::md_kroki::{postprocess, MdKroki, MdKrokiBuilder, RenderCache};
+use mdbook::Config;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Settings for the kroki preprocessor.
+#[derive(Deserialize, Debug)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct KrokiConfig {
+    /// Kroki deployment to send diagrams to.
+    pub endpoint: String,
+
+    /// Endpoints for specific diagram types, overriding [endpoint][Self::endpoint].
+    pub endpoints: BTreeMap<String, String>,
+
+    /// Endpoints tried in order when a diagram's endpoint is unreachable or returns a 5xx.
+    pub fallback_endpoints: Vec<String>,
+
+    /// Directory of the render cache, relative to the book root.
+    /// Renders are only cached if this is set or [offline][Self::offline] is enabled.
+    pub cache_dir: Option<PathBuf>,
+
+    /// Never contact kroki; serve every diagram from the render cache.
+    pub offline: bool,
+
+    /// Static headers sent with every request.
+    pub headers: BTreeMap<String, String>,
+
+    /// Name of the environment variable holding a bearer token.
+    pub token_env: Option<String>,
+
+    /// Only present to reject tokens written directly into `book.toml`.
+    token: Option<IgnoredAny>,
+
+    /// HTTP(S) proxy for all requests.
+    pub proxy: Option<String>,
+
+    /// PEM file with an extra CA certificate to trust, relative to the book root.
+    pub ca_cert: Option<PathBuf>,
+
+    /// PEM file with a client certificate and its private key, relative to the book root.
+    pub client_cert: Option<PathBuf>,
+
+    /// Directories outside the book root that referenced files may be read from,
+    /// relative to the book root.
+    pub allowed_dirs: Vec<PathBuf>,
+
+    /// Allow references with `root="system"`.
+    pub allow_system_root: bool,
+
+    /// Extra file extensions and the diagram types they imply, like `wsd = "plantuml"`.
+    pub extensions: BTreeMap<String, String>,
+
+    /// Language of this build, replacing `{lang}` in file references.
+    /// Defaults to `book.language`.
+    pub language: Option<String>,
+
+    /// Language whose files and labels are used when the build language has none.
+    pub default_language: Option<String>,
+
+    /// Strings for `{{label:key}}` placeholders in inline diagrams, by language.
+    pub labels: BTreeMap<String, BTreeMap<String, String>>,
+
+    /// Rows kept from a data file merged into a Vega or Vega-Lite spec; defaults to
+    /// [DEFAULT_DATA_ROW_LIMIT][boilerplate::md_kroki::DEFAULT_DATA_ROW_LIMIT].
+    pub data_row_limit: Option<usize>,
+
+    /// Directory, relative to the book root, to record the rendered diagrams of each build in,
+    /// for comparing builds with `diff-report`.
+    pub manifest_dir: Option<PathBuf>,
+
+    /// File, relative to the book root, to write render statistics of each build to as JSON.
+    pub stats_file: Option<PathBuf>,
+
+    /// Commands that `<kroki generate="name">` tags may run, by name.
+    pub generators: BTreeMap<String, GeneratorConfig>,
+
+    /// Built-in post-processors applied to every diagram.
+    pub post_process: PostProcessConfig,
+}
+
+/// Settings of the `[preprocessor.kroki-preprocessor.post-process]` table.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PostProcessConfig {
+    /// Minify the svg of every diagram.
+    pub optimize: bool,
+
+    /// Fractional digits kept when optimizing; defaults to [DEFAULT_PRECISION].
+    pub precision: Option<usize>,
+
+    /// Colors to replace in the svg, like `"#FEFECE" = "#E8F0FE"`.
+    pub colors: BTreeMap<String, String>,
+
+    /// Extra classes of the element wrapping each diagram.
+    pub classes: Vec<String>,
+
+    /// `data-` attributes of the element wrapping each diagram, without the `data-` prefix.
+    pub data_attributes: BTreeMap<String, String>,
+}
+
+impl Default for KrokiConfig {
+    fn default() -> Self {
+        KrokiConfig {
+            endpoint: "https://kroki.io/".to_string(),
+            endpoints: BTreeMap::new(),
+            fallback_endpoints: Vec::new(),
+            cache_dir: None,
+            offline: false,
+            headers: BTreeMap::new(),
+            token_env: None,
+            token: None,
+            proxy: None,
+            ca_cert: None,
+            client_cert: None,
+            allowed_dirs: Vec::new(),
+            allow_system_root: false,
+            extensions: BTreeMap::new(),
+            language: None,
+            default_language: None,
+            labels: BTreeMap::new(),
+            data_row_limit: None,
+            manifest_dir: None,
+            stats_file: None,
+            generators: BTreeMap::new(),
+            post_process: PostProcessConfig::default(),
+        }
+    }
+}
+
+/// Fractional digits kept by the svg optimizer unless `precision` is set.
+pub const DEFAULT_PRECISION: usize = 2;
+
+/// Cache directory used when `offline` is set without a `cache-dir`.
+const DEFAULT_CACHE_DIR: &str = ".kroki-cache";
+
+impl KrokiConfig {
+    /// Read the settings of the preprocessor called `name` from the book config.
+    pub fn load(config: &Config, name: &str) -> Result<Self> {
+        let mut kroki_config = config
+            .get_deserialized_opt::<KrokiConfig, _>(format!("preprocessor.{name}"))
+            .with_context(|| format!("invalid [preprocessor.{name}] configuration"))?
+            .unwrap_or_default();
+
+        if kroki_config.token.is_some() {
+            bail!("[preprocessor.{name}] must not contain a token; put it in an environment variable and set `token-env` instead");
+        }
+
+        for endpoint in std::iter::once(&mut kroki_config.endpoint)
+            .chain(kroki_config.endpoints.values_mut())
+            .chain(&mut kroki_config.fallback_endpoints)
+        {
+            if !endpoint.ends_with('/') {
+                endpoint.push('/');
+            }
+        }
+
+        for (generator, settings) in &kroki_config.generators {
+            if settings.command.is_empty() {
+                bail!("generator {generator:?} must have a non-empty `command`");
+            }
+        }
+
+        if kroki_config.language.is_none() {
+            kroki_config.language = config.book.language.clone();
+        }
+
+        Ok(kroki_config)
+    }
+
+    /// The render cache of a book, whether or not the build uses it.
+    pub fn cache_dir(&self, book_root: &Path) -> PathBuf {
+        book_root.join(self.cache_dir.as_deref().unwrap_or(Path::new(DEFAULT_CACHE_DIR)))
+    }
+
+    /// Build the renderer shared by every chapter of the book.
+    ///
+    /// Use [MdKroki::for_document] with a chapter's source path to render that chapter.
+    /// Fails if a configured certificate file, allowed directory or the token environment variable
+    /// can't be read.
+    pub fn renderer(&self, book_root: PathBuf, source_root: PathBuf) -> Result<MdKroki> {
+        self.renderer_builder(book_root, source_root)?.try_build()
+    }
+
+    /// The configured builder behind [renderer][Self::renderer], for adding build-only hooks.
+    pub fn renderer_builder(&self, book_root: PathBuf, source_root: PathBuf) -> Result<MdKrokiBuilder> {
+        let mut builder = MdKroki::builder()
+            .endpoint(&self.endpoint)
+            .offline(self.offline);
+        for (diagram_type, endpoint) in &self.endpoints {
+            builder = builder.route(diagram_type, endpoint);
+        }
+        for endpoint in &self.fallback_endpoints {
+            builder = builder.fallback_endpoint(endpoint);
+        }
+        for (extension, diagram_type) in &self.extensions {
+            builder = builder.extension(extension, diagram_type);
+        }
+        if let Some(limit) = self.data_row_limit {
+            builder = builder.data_row_limit(limit);
+        }
+        if let Some(language) = &self.language {
+            builder = builder.language(language);
+        }
+        if let Some(language) = &self.default_language {
+            builder = builder.default_language(language);
+        }
+        for (language, labels) in &self.labels {
+            builder = builder.labels(language, labels.clone());
+        }
+        let cache_dir = (self.cache_dir.is_some() || self.offline).then(|| self.cache_dir(&book_root));
+        if let Some(dir) = &cache_dir {
+            builder = builder.cache(RenderCache::new(dir));
+        }
+        if !self.generators.is_empty() {
+            let generators = Generators::new(
+                book_root.clone(),
+                self.generators.clone(),
+                cache_dir.map(|dir| dir.join("generated")),
+            );
+            builder = builder.source_generator(move |name| generators.generate(name));
+        }
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        if let Some(var) = &self.token_env {
+            let token = std::env::var(var)
+                .with_context(|| format!("could not read kroki token from ${var}"))?;
+            builder = builder.bearer_token(token);
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy);
+        }
+        let read_pem = |path: &Path| {
+            let path = book_root.join(path);
+            std::fs::read(&path).with_context(|| format!("could not read {}", path.display()))
+        };
+        if let Some(path) = &self.ca_cert {
+            builder = builder.ca_certificate(read_pem(path)?);
+        }
+        if let Some(path) = &self.client_cert {
+            builder = builder.client_certificate(read_pem(path)?);
+        }
+        let post_process = &self.post_process;
+        if post_process.optimize {
+            let precision = post_process.precision.unwrap_or(DEFAULT_PRECISION);
+            builder = builder.post_process(postprocess::optimize(precision));
+        }
+        if !post_process.colors.is_empty() {
+            builder = builder.post_process(postprocess::replace_colors(post_process.colors.clone()));
+        }
+        if !post_process.classes.is_empty() {
+            builder = builder.post_process(postprocess::add_classes(post_process.classes.clone()));
+        }
+        if !post_process.data_attributes.is_empty() {
+            builder = builder.post_process(postprocess::add_data_attributes(
+                post_process.data_attributes.clone(),
+            ));
+        }
+
+        let policy = PathPolicy::new(&book_root, &self.allowed_dirs, self.allow_system_root)?;
+
+        let builder = builder
+            .document_file_resolver(move |mut path, root: Option<&str>, chapter_path: Option<&Path>| {
+                // 根据root配置解析文件路径
+                let full_path = match root {
+                    Some("system") => {
+                        policy.check_system_root()?;
+                        if path.is_relative() {
+                            bail!("cannot use relative path with root=\"system\"");
+                        }
+                        path
+                    }
+                    Some("book") => {
+                        if path.is_absolute() {
+                            path = path.strip_prefix("/")?.into();
+                        }
+                        book_root.join(path)
+                    }
+                    Some("source" | "src") => {
+                        if path.is_absolute() {
+                            path = path.strip_prefix("/")?.into();
+                        }
+                        book_root.join(&source_root).join(path)
+                    }
+                    None | Some("this" | ".") => {
+                        if path.is_absolute() {
+                            bail!(r#"cannot use absolute path without setting `root` attribute to "system", "book", or "source""#);
+                        }
+                        let chapter_parent_path = chapter_path
+                            .map(|p| p.parent().unwrap_or(Path::new("")))
+                            .ok_or_else(|| anyhow!("cannot use local relative file references in chapters with no source path."))?;
+                        book_root
+                            .join(&source_root)
+                            .join(chapter_parent_path)
+                            .join(path)
+                    }
+                    Some(other) => bail!("unrecognized root type: {other}")
+                };
+
+                let source = policy.read(&full_path)?;
+                Ok((full_path, source))
+            });
+        Ok(builder)
+    }
+}
@@ -0,0 +1,135 @@
+//! The `diff-report` subcommand: an HTML page comparing the diagrams of two builds, showing
+//! those that render differently although their source is unchanged, e.g. after upgrading the
+//! kroki deployment or a shared theme.
+
+use crate::manifest::{Manifest, ManifestEntry};
+use anyhow::Result;
+use boilerplate::md_kroki::escape_html;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Kroki diagram changes</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+h2 small { color: #888; font-weight: normal; }
+.pair { display: flex; gap: 1em; }
+figure { flex: 1; margin: 0; }
+iframe { width: 100%; height: 400px; border: 1px solid #ccc; resize: vertical; }
+</style>
+</head>
+<body>
+<h1>Kroki diagram changes</h1>
+<p>{summary}</p>
+{sections}
+</body>
+</html>
+"#;
+
+/// Arguments of the `diff-report` subcommand.
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("diff-report")
+        .about("Compare the diagrams of two builds and show the ones that render differently")
+        .arg(
+            Arg::with_name("before")
+                .required(true)
+                .help("Manifest directory of the earlier build"),
+        )
+        .arg(
+            Arg::with_name("after")
+                .required(true)
+                .help("Manifest directory of the later build"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .default_value("kroki-diff.html")
+                .help("Path of the HTML report"),
+        )
+}
+
+/// Runs the `diff-report` subcommand.
+pub fn handle(args: &ArgMatches) -> Result<()> {
+    let before = Path::new(args.value_of("before").expect("required"));
+    let after = Path::new(args.value_of("after").expect("required"));
+    let output = PathBuf::from(args.value_of("output").expect("has default"));
+
+    let (old_manifest, new_manifest) = (Manifest::load(before)?, Manifest::load(after)?);
+    let (compared, changed) = changed_diagrams(&old_manifest, &new_manifest);
+    let mut sections = String::new();
+    for (old, new) in &changed {
+        let location = match &new.file {
+            Some(file) => format!("{}:{}", file.display(), new.line),
+            None => format!("line {}", new.line),
+        };
+        sections.push_str(&format!(
+            "<section>\n<h2>{} <small>{}</small></h2>\n<div class=\"pair\">\n{}\n{}\n</div>\n</section>\n",
+            escape_html(&location),
+            escape_html(&new.diagram_type),
+            figure("Before", &Manifest::output(before, old)?),
+            figure("After", &Manifest::output(after, new)?),
+        ));
+    }
+    let summary = format!(
+        "{} of {compared} diagrams with unchanged sources render differently.",
+        changed.len()
+    );
+    let page = PAGE
+        .replace("{summary}", &summary)
+        .replace("{sections}", &sections);
+    fs::write(&output, page)?;
+    log::info!("{summary} Report written to {}", output.display());
+    Ok(())
+}
+
+/// Pairs of entries with the same chapter and source whose output differs, and the number of
+/// pairs compared. Diagrams whose source changed, or that only exist in one build, are skipped.
+pub(crate) fn changed_diagrams<'a>(
+    before: &'a Manifest,
+    after: &'a Manifest,
+) -> (usize, Vec<(&'a ManifestEntry, &'a ManifestEntry)>) {
+    // Match by source rather than line, so edits elsewhere in a chapter don't matter.
+    let group = |manifest: &'a Manifest| {
+        let mut groups: BTreeMap<_, Vec<&ManifestEntry>> = BTreeMap::new();
+        for entry in &manifest.diagrams {
+            groups
+                .entry((&entry.file, &entry.source_hash))
+                .or_default()
+                .push(entry);
+        }
+        groups
+    };
+    let before = group(before);
+    let mut compared = 0;
+    let mut changed = Vec::new();
+    for (key, new_entries) in group(after) {
+        let Some(old_entries) = before.get(&key) else {
+            continue;
+        };
+        // Identical diagrams in one chapter are paired in order.
+        for (old, new) in old_entries.iter().zip(new_entries) {
+            compared += 1;
+            if old.output_hash != new.output_hash {
+                changed.push((*old, new));
+            }
+        }
+    }
+    (compared, changed)
+}
+
+/// One side of a comparison. The html is isolated in a frame so the ids and styles of the two
+/// svgs can't clash.
+fn figure(caption: &str, html: &str) -> String {
+    let document = format!("<!DOCTYPE html><html><body style=\"margin: 0\">{html}</body></html>");
+    format!(
+        "<figure><figcaption>{caption}</figcaption><iframe srcdoc=\"{}\"></iframe></figure>",
+        escape_html(&document)
+    )
+}
@@ -0,0 +1,178 @@
+//! Diagram sources generated by commands declared in `book.toml`.
+//!
+//! A chapter only names a generator; its command line, working directory and timeout come from
+//! the book config, so markdown can never run arbitrary commands.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Settings of one `[preprocessor.kroki-preprocessor.generators.<name>]` table.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GeneratorConfig {
+    /// Program and arguments. Not run through a shell.
+    pub command: Vec<String>,
+
+    /// Working directory, relative to the book root. Defaults to the book root.
+    pub cwd: Option<PathBuf>,
+
+    /// Seconds the command may run before it is killed.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+
+    /// Files the output depends on, relative to the book root. If any are given, the output is
+    /// stored in the render cache and the command only runs again when one of them changes.
+    #[serde(default)]
+    pub inputs: Vec<PathBuf>,
+}
+
+fn default_timeout() -> u64 {
+    60
+}
+
+/// Runs the declared generators, each at most once per build.
+pub struct Generators {
+    book_root: PathBuf,
+    generators: BTreeMap<String, GeneratorConfig>,
+    cache_dir: Option<PathBuf>,
+    /// The output of each generator that ran, behind its own lock.
+    outputs: Mutex<HashMap<String, Arc<Mutex<Option<String>>>>>,
+}
+
+impl Generators {
+    /// Generators run in `book_root`, storing outputs in `cache_dir` if one is given.
+    pub fn new(
+        book_root: PathBuf,
+        generators: BTreeMap<String, GeneratorConfig>,
+        cache_dir: Option<PathBuf>,
+    ) -> Self {
+        Generators {
+            book_root,
+            generators,
+            cache_dir,
+            outputs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The stdout of the generator called `name`.
+    pub fn generate(&self, name: &str) -> Result<String> {
+        let generator = self.generators.get(name).ok_or_else(|| {
+            anyhow!("unknown generator {name:?}; declare it in [preprocessor.kroki-preprocessor.generators.{name}]")
+        })?;
+        let entry = Arc::clone(
+            self.outputs
+                .lock()
+                .expect("generator lock poisoned")
+                .entry(name.to_string())
+                .or_default(),
+        );
+        // Held while the command runs, so chapters sharing a generator don't run it twice, while
+        // other generators run in parallel. A failure leaves the entry empty for the next chapter.
+        let mut slot = entry.lock().expect("generator lock poisoned");
+        if let Some(output) = &*slot {
+            return Ok(output.clone());
+        }
+
+        let cache_entry = match &self.cache_dir {
+            Some(dir) if !generator.inputs.is_empty() => Some(dir.join(self.input_key(generator)?)),
+            _ => None,
+        };
+        let output = match cache_entry.as_deref().map(fs::read_to_string) {
+            Some(Ok(output)) => {
+                log::debug!("generator {name:?} inputs unchanged, using cached output");
+                output
+            }
+            _ => {
+                log::info!("running generator {name:?}");
+                let output = self
+                    .run(generator)
+                    .with_context(|| format!("generator {name:?} failed"))?;
+                if let Some(entry) = &cache_entry {
+                    store(entry, &output)?;
+                }
+                output
+            }
+        };
+        *slot = Some(output.clone());
+        Ok(output)
+    }
+
+    /// Hash of the command line, working directory and the contents of every input.
+    fn input_key(&self, generator: &GeneratorConfig) -> Result<String> {
+        let mut hasher = Sha256::new();
+        for arg in &generator.command {
+            hasher.update(arg.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.update(generator.cwd.as_deref().unwrap_or(Path::new("")).to_string_lossy().as_bytes());
+        for input in &generator.inputs {
+            let path = self.book_root.join(input);
+            let content = fs::read(&path)
+                .with_context(|| format!("could not read generator input {}", path.display()))?;
+            hasher.update([0]);
+            hasher.update(input.to_string_lossy().as_bytes());
+            hasher.update([0]);
+            hasher.update(Sha256::digest(&content));
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    fn run(&self, generator: &GeneratorConfig) -> Result<String> {
+        let (program, args) = generator.command.split_first().ok_or_else(|| anyhow!("command is empty"))?;
+        let cwd = self.book_root.join(generator.cwd.as_deref().unwrap_or(Path::new("")));
+        let mut child = Command::new(program)
+            .args(args)
+            .current_dir(&cwd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("could not run {program} in {}", cwd.display()))?;
+
+        // Read both pipes while waiting, so a chatty command can't block on a full pipe.
+        let stdout = read_in_background(child.stdout.take().expect("stdout is piped"));
+        let stderr = read_in_background(child.stderr.take().expect("stderr is piped"));
+        let deadline = Instant::now() + Duration::from_secs(generator.timeout);
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                bail!("timed out after {}s", generator.timeout);
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        let stdout = stdout.join().expect("stdout reader panicked")?;
+        if !status.success() {
+            let stderr = stderr.join().expect("stderr reader panicked")?;
+            bail!("{status}: {}", String::from_utf8_lossy(&stderr).trim());
+        }
+        String::from_utf8(stdout).context("output is not UTF-8")
+    }
+}
+
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        pipe.read_to_end(&mut buffer)?;
+        Ok(buffer)
+    })
+}
+
+fn store(entry: &Path, output: &str) -> Result<()> {
+    let dir = entry.parent().expect("cache entries are in a directory");
+    fs::create_dir_all(dir).with_context(|| format!("could not create {}", dir.display()))?;
+    fs::write(entry, output).with_context(|| format!("could not write {}", entry.display()))
+}
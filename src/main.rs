@@ -1,20 +1,49 @@
 #![doc = include_str!("../README.md")]
-// add md_kroki folder
-mod md_kroki;
-
-use anyhow::{anyhow, bail, Result};
-use futures::Future;
-use md_kroki::MdKroki;
+mod cache;
+mod check;
+mod config;
+mod diff_report;
+mod generate;
+mod manifest;
+mod paths;
+mod preview;
+mod stats;
+#[cfg(test)]
+mod temp_dir;
+#[cfg(test)]
+mod test;
+
+use anyhow::{bail, Result};
+use boilerplate::md_kroki::DocumentId;
+use config::KrokiConfig;
+use manifest::ManifestRecorder;
 use mdbook::book::{Book, BookItem, Chapter};
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
-use std::path::PathBuf;
-use std::pin::Pin;
+use stats::StatsRecorder;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
 /// 主函数，使用mdbook预处理器样板启动Kroki预处理
 fn main() {
-    boilerplate::run(
+    // 默认输出info级别日志，可通过RUST_LOG覆盖
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    boilerplate::run_with_subcommands(
         KrokiPreprocessor,
         "An mdbook preprocessor for rendering kroki diagrams",
+        vec![
+            check::subcommand(),
+            cache::subcommand(),
+            preview::subcommand(),
+            diff_report::subcommand(),
+        ],
+        |name, args| match name {
+            "check" => check::handle(args),
+            "cache" => cache::handle(args),
+            "preview" => preview::handle(args),
+            "diff-report" => diff_report::handle(args),
+            _ => unreachable!("unhandled subcommand {name}"),
+        },
     );
 }
 
@@ -29,83 +58,28 @@ impl Preprocessor for KrokiPreprocessor {
 
     /// 主处理逻辑
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        // 获取Kroki端点配置
-        let endpoint = if let Some(v) = ctx
-            .config
-            .get_preprocessor(self.name())
-            .and_then(|config| config.get("endpoint"))
-        {
-            if let Some(s) = v.as_str() {
-                let mut url = s.to_string();
-                if !url.ends_with('/') {
-                    url.push('/');
-                }
-                url
-            } else {
-                bail!("endpoint must be a string")
-            }
-        } else {
-            "https://kroki.io/".to_string()
-        };
-
-        let source_root = &ctx.config.book.src;
-        let book_root = ctx.root.clone();
-
-        // 创建渲染器工厂闭包
-        let renderer_factory = move |chapter_path: Option<PathBuf>| {
-            let source_root = source_root.clone();
-            let book_root = book_root.clone();
-            let chapter_parent_path = chapter_path.map(|mut p| {
-                p.pop();
-                p
-            });
-            
-            MdKroki::builder()
-                .endpoint(endpoint.clone())
-                .path_and_root_resolver(move |mut path, root: Option<&str>| {
-                    // 根据root配置解析文件路径
-                    let full_path = match root {
-                        Some("system") => {
-                            if path.is_relative() {
-                                bail!("cannot use relative path with root=\"system\"");
-                            }
-                            path
-                        }
-                        Some("book") => {
-                            if path.is_absolute() {
-                                path = path.strip_prefix("/")?.into();
-                            }
-                            book_root.join(path)
-                        }
-                        Some("source" | "src") => {
-                            if path.is_absolute() {
-                                path = path.strip_prefix("/")?.into();
-                            }
-                            book_root.join(&source_root).join(path)
-                        }
-                        None | Some("this" | ".") => {
-                            if path.is_absolute() {
-                                bail!(r#"cannot use absolute path without setting `root` attribute to "system", "book", or "source""#);
-                            }
-                            book_root
-                                .join(&source_root)
-                                .join(
-                                chapter_parent_path.as_deref().ok_or_else(|| anyhow!("cannot use local relative file references in chapters with no source path."))?
-                                )
-                                .join(path)
-                        }
-                        Some(other) => bail!("unrecognized root type: {other}")
-                    };
-
-                    Ok(std::fs::read_to_string(full_path)?)
-                })
-                .build()
-        };
-
-        // 收集所有渲染任务
+        // 读取book.toml中的预处理器配置
+        let config = KrokiConfig::load(&ctx.config, self.name())?;
+        // 整本书共用一个渲染器（以及其中的HTTP连接池）
+        let mut builder = config.renderer_builder(ctx.root.clone(), ctx.config.book.src.clone())?;
+        // 配置了manifest-dir时，记录每个图表的源码和输出哈希，供diff-report比较
+        let manifest = config
+            .manifest_dir
+            .as_ref()
+            .map(|dir| Arc::new(ManifestRecorder::new(ctx.root.join(dir))));
+        if let Some(manifest) = &manifest {
+            let manifest = manifest.clone();
+            builder = builder.on_render(move |document, spec, html| manifest.record(document, spec, html));
+        }
+        // 记录每个图表的耗时、缓存命中和失败情况，运行结束时输出汇总
+        let stats = Arc::new(StatsRecorder::default());
+        let recorder = stats.clone();
+        builder = builder.on_fetch(move |fetch| recorder.record(fetch));
+        let renderer = builder.try_build()?;
+
+        // 收集所有章节，整本书一起渲染，相同的图只请求一次
         let mut index_stack = vec![];
-        let render_futures =
-            extract_render_futures(&mut book.sections, &mut index_stack, &renderer_factory);
+        let chapters = extract_chapters(&mut book.sections, &mut index_stack);
 
         // 创建多线程运行时并执行所有任务
         let rt = tokio::runtime::Builder::new_multi_thread()
@@ -113,14 +87,52 @@ impl Preprocessor for KrokiPreprocessor {
             .build()
             .expect("Failed to create multi-threaded runtime");
 
-        let rendered_files = rt.block_on(async {
-            futures::future::join_all(render_futures.into_iter()).await
-        }).into_iter().collect::<Result<Vec<RenderedFile>>>()?;
+        // 保留章节原文，渲染失败时逐个图表统计请求前就失败的图表
+        let sources: Vec<_> = chapters
+            .iter()
+            .map(|(chapter, content)| (chapter.source_path.clone(), content.clone()))
+            .collect();
+
+        let start = Instant::now();
+        let results = rt.block_on(renderer.render_many(chapters));
+        // 格式错误、文件缺失等图表在请求之前就失败了，不会被记录，需要单独计数
+        let unsent = results
+            .iter()
+            .zip(&sources)
+            .filter(|((_, result), _)| result.is_err())
+            .map(|(_, (path, content))| {
+                let specs = renderer.for_document(path.clone()).extract_each(content);
+                specs.iter().filter(|spec| spec.is_err()).count()
+            })
+            .sum();
+        // 失败时也输出统计，便于在CI中定位问题
+        let render_stats = stats.finish(start.elapsed(), unsent);
+        render_stats.log();
+        if let Some(stats_file) = &config.stats_file {
+            render_stats.write(&ctx.root.join(stats_file))?;
+        }
+
+        // 汇总所有章节的错误，而不是只报告第一个
+        let mut rendered_files = Vec::new();
+        let mut errors = Vec::new();
+        for (chapter, result) in results {
+            match result {
+                Ok(content) => rendered_files.push((chapter, content)),
+                Err(e) => errors.push(format!("chapter \"{}\": {e:#}", chapter.name)),
+            }
+        }
+        if !errors.is_empty() {
+            bail!("{} chapters failed to render:\n{}", errors.len(), errors.join("\n"));
+        }
+
+        if let Some(manifest) = &manifest {
+            manifest.finish()?;
+        }
 
         // 更新处理后的内容到书籍
-        for file in rendered_files {
+        for (file, content) in rendered_files {
             let chapter = get_chapter(&mut book.sections, &file.indices);
-            chapter.content = file.content;
+            chapter.content = content;
         }
 
         Ok(book)
@@ -132,45 +144,35 @@ impl Preprocessor for KrokiPreprocessor {
     }
 }
 
-/// 递归收集所有章节的渲染任务
-fn extract_render_futures<'a>(
-    items: impl IntoIterator<Item = &'a mut BookItem> + 'a,
+/// 递归收集所有章节及其内容
+fn extract_chapters<'a>(
+    items: impl IntoIterator<Item = &'a mut BookItem>,
     indices: &mut Vec<usize>,
-    renderer_factory: &'a impl Fn(Option<PathBuf>) -> MdKroki,
-) -> Vec<Pin<Box<dyn Future<Output = Result<RenderedFile>> + 'a>>> {
-    let mut files = Vec::new();
+) -> Vec<(ChapterRef, String)> {
+    let mut chapters = Vec::new();
     indices.push(0);
     for (index, item) in items.into_iter().enumerate() {
         if let BookItem::Chapter(ref mut chapter) = item {
-            let chapter_source = chapter.source_path.clone();
             let chapter_content = chapter.content.split_off(0);
             *indices.last_mut().unwrap() = index;
-            let indices_clone = indices.clone();
-            
+            let chapter_ref = ChapterRef {
+                indices: indices.clone(),
+                name: chapter.name.clone(),
+                source_path: chapter.source_path.clone(),
+            };
+
             // 递归处理子章节
-            files.extend(extract_render_futures(
-                &mut chapter.sub_items,
-                indices,
-                renderer_factory,
-            ));
-            
-            // 为当前章节创建渲染任务
-            files.push(Box::pin(async move {
-                let renderer = renderer_factory(chapter_source);
-                let new_content = renderer.render(chapter_content).await?;
-                Ok(RenderedFile {
-                    indices: indices_clone,
-                    content: new_content,
-                })
-            }));
+            chapters.extend(extract_chapters(&mut chapter.sub_items, indices));
+
+            chapters.push((chapter_ref, chapter_content));
         }
     }
     indices.pop();
-    files
+    chapters
 }
 
 /// 根据索引路径获取对应章节的可变引用
-fn get_chapter<'a>(mut items: &'a mut Vec<BookItem>, indices: &Vec<usize>) -> &'a mut Chapter {
+fn get_chapter<'a>(mut items: &'a mut Vec<BookItem>, indices: &[usize]) -> &'a mut Chapter {
     for index in &indices[..indices.len() - 1] {
         let item = items.get_mut(*index).expect("index disappeared");
         match item {
@@ -187,8 +189,15 @@ fn get_chapter<'a>(mut items: &'a mut Vec<BookItem>, indices: &Vec<usize>) -> &'
     }
 }
 
-/// 渲染结果结构，包含章节索引和处理后的内容
-struct RenderedFile {
+/// 章节标识，包含章节索引、名称和源文件路径
+struct ChapterRef {
     indices: Vec<usize>,
-    content: String,
-}
\ No newline at end of file
+    name: String,
+    source_path: Option<PathBuf>,
+}
+
+impl DocumentId for ChapterRef {
+    fn document_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+}
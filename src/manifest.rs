@@ -0,0 +1,131 @@
+//! A record of every diagram rendered by a build, for comparing builds with `diff-report`.
+//!
+//! The manifest directory holds `manifest.json`, listing each diagram's location and the hashes
+//! of its source and rendered html, and `outputs/<output hash>.html` with the html itself.
+
+use anyhow::{Context, Result};
+use boilerplate::md_kroki::DiagramSpec;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const OUTPUTS_DIR: &str = "outputs";
+
+/// The contents of `manifest.json`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    /// Sorted by chapter and line.
+    pub diagrams: Vec<ManifestEntry>,
+}
+
+/// One rendered diagram.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub struct ManifestEntry {
+    /// Chapter file, relative to the book's source directory.
+    pub file: Option<PathBuf>,
+    /// First line of the diagram in the chapter.
+    pub line: usize,
+    /// Kroki diagram type.
+    #[serde(rename = "type")]
+    pub diagram_type: String,
+    /// SHA-256 of the diagram type and source.
+    pub source_hash: String,
+    /// SHA-256 of the html inlined into the chapter.
+    pub output_hash: String,
+}
+
+impl Manifest {
+    /// Read the manifest in `dir`.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        serde_json::from_str(&content).with_context(|| format!("invalid manifest {}", path.display()))
+    }
+
+    /// The rendered html of an entry of the manifest in `dir`.
+    pub fn output(dir: &Path, entry: &ManifestEntry) -> Result<String> {
+        let path = dir.join(OUTPUTS_DIR).join(format!("{}.html", entry.output_hash));
+        fs::read_to_string(&path).with_context(|| format!("could not read {}", path.display()))
+    }
+}
+
+/// Collects rendered diagrams during a build and writes the manifest at the end.
+pub struct ManifestRecorder {
+    dir: PathBuf,
+    rendered: Mutex<Vec<(ManifestEntry, String)>>,
+}
+
+impl ManifestRecorder {
+    /// Record into the manifest directory `dir`, replacing its contents when [finished][Self::finish].
+    pub fn new(dir: PathBuf) -> Self {
+        ManifestRecorder {
+            dir,
+            rendered: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Record a diagram inlined into the chapter at `document`.
+    pub fn record(&self, document: Option<&Path>, spec: &DiagramSpec, html: &str) {
+        let mut source = Sha256::new();
+        source.update(spec.diagram_type.as_bytes());
+        source.update([0]);
+        source.update(spec.source.as_bytes());
+        let entry = ManifestEntry {
+            file: document.map(Path::to_path_buf),
+            line: *spec.lines.start(),
+            diagram_type: spec.diagram_type.clone(),
+            source_hash: format!("{:x}", source.finalize()),
+            output_hash: format!("{:x}", Sha256::digest(html.as_bytes())),
+        };
+        self.rendered
+            .lock()
+            .expect("manifest lock poisoned")
+            .push((entry, html.to_string()));
+    }
+
+    /// Write the manifest and the outputs, and remove outputs no longer referenced.
+    ///
+    /// Takes the diagrams recorded so far, so the renderer may still hold a reference.
+    pub fn finish(&self) -> Result<()> {
+        let mut rendered = std::mem::take(&mut *self.rendered.lock().expect("manifest lock poisoned"));
+        rendered.sort();
+
+        let outputs = self.dir.join(OUTPUTS_DIR);
+        fs::create_dir_all(&outputs)
+            .with_context(|| format!("could not create {}", outputs.display()))?;
+        let mut written = BTreeSet::new();
+        for (entry, html) in &rendered {
+            let name = format!("{}.html", entry.output_hash);
+            let path = outputs.join(&name);
+            if !path.exists() {
+                fs::write(&path, html).with_context(|| format!("could not write {}", path.display()))?;
+            }
+            written.insert(name);
+        }
+        for old in fs::read_dir(&outputs)? {
+            let old = old?;
+            if !old.file_name().to_str().is_some_and(|name| written.contains(name)) {
+                fs::remove_file(old.path())?;
+            }
+        }
+
+        let manifest = Manifest {
+            diagrams: rendered.into_iter().map(|(entry, _)| entry).collect(),
+        };
+        let path = self.dir.join(MANIFEST_FILE);
+        fs::write(&path, serde_json::to_string_pretty(&manifest)?)
+            .with_context(|| format!("could not write {}", path.display()))?;
+        log::info!(
+            "recorded {} diagrams in {}",
+            manifest.diagrams.len(),
+            path.display()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! On-disk cache of kroki responses, keyed by the request that produced them.
+
+use crate::md_kroki::render::RenderRequest;
+use crate::md_kroki::DiagramSpec;
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Distinguishes the temporary files of concurrent writes from this process.
+static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory of cached renders.
+///
+/// Each entry is a single file named after the hex SHA-256 of the kroki request, so entries
+/// can be copied between machines freely.
+#[derive(Debug, Clone)]
+pub struct RenderCache {
+    dir: PathBuf,
+}
+
+impl RenderCache {
+    /// Use `dir` as the cache directory. It is created on the first write.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        RenderCache { dir: dir.into() }
+    }
+
+    /// The cache directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub(crate) fn key(spec: &DiagramSpec) -> String {
+        let request = serde_json::to_string(&RenderRequest::from(spec))
+            .expect("could no serialize kroki request");
+        format!("{:x}", Sha256::digest(request.as_bytes()))
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Result<Option<String>> {
+        match fs::read_to_string(self.dir.join(key)) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => {
+                Err(e).with_context(|| format!("could not read cache entry {key}"))
+            }
+        }
+    }
+
+    pub(crate) fn put(&self, key: &str, content: &str) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("could not create cache dir {}", self.dir.display()))?;
+        // Write to a temporary file first so concurrent readers never see partial entries. The
+        // name is unique to this write, as several writers may store the same key at once.
+        let temp = self.dir.join(format!(
+            ".{key}.{}.{}.tmp",
+            std::process::id(),
+            NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temp, content)?;
+        fs::rename(&temp, self.dir.join(key)).inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })?;
+        Ok(())
+    }
+
+    /// Write every cache entry into a tar archive. Returns the number of entries written.
+    pub fn export(&self, archive: &Path) -> Result<usize> {
+        let mut builder = tar::Builder::new(
+            File::create(archive)
+                .with_context(|| format!("could not create {}", archive.display()))?,
+        );
+        let mut count = 0;
+        if self.dir.is_dir() {
+            for entry in fs::read_dir(&self.dir)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                if entry.file_type()?.is_file() && name.to_str().is_some_and(is_key) {
+                    builder.append_path_with_name(entry.path(), name)?;
+                    count += 1;
+                }
+            }
+        }
+        builder.finish()?;
+        Ok(count)
+    }
+
+    /// Add every entry of a tar archive made by [export][Self::export] to the cache.
+    /// Returns the number of entries imported.
+    pub fn import(&self, archive: &Path) -> Result<usize> {
+        let mut archive = tar::Archive::new(
+            File::open(archive).with_context(|| format!("could not open {}", archive.display()))?,
+        );
+        let mut count = 0;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            // Only accept bare key names so an archive can't write outside the cache dir.
+            let Some(name) = path.to_str().filter(|name| is_key(name)) else {
+                bail!("unexpected entry in cache archive: {}", path.display());
+            };
+            let mut content = String::new();
+            entry
+                .read_to_string(&mut content)
+                .with_context(|| format!("could not read {name} from cache archive"))?;
+            self.put(name, &content)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+fn is_key(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Error returned in offline mode for diagrams that aren't in the render cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheMiss {
+    /// Type of the diagram that was requested.
+    pub diagram_type: String,
+    /// Cache key the render would be stored under.
+    pub key: String,
+}
+
+impl std::fmt::Display for CacheMiss {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} diagram is not in the render cache (key {})",
+            self.diagram_type, self.key
+        )
+    }
+}
+
+impl std::error::Error for CacheMiss {}
@@ -0,0 +1,180 @@
+//! Data files merged into Vega and Vega-Lite specs, so rows don't have to be inlined by hand.
+//!
+//! A `data="metrics.csv"` attribute reads the file through the path resolver, parses it and
+//! puts the rows into the spec as inline `values`. CSV columns are typed by their contents:
+//! a column whose cells are all plain decimal numbers (or all booleans) becomes numbers (or
+//! booleans), with empty cells as `null`; anything else stays a string. Cells like `007` or
+//! `1e3` count as text, so zip codes, ids and version-like codes survive unchanged.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Map, Value};
+use std::path::Path;
+
+/// Rows kept from a data file unless [data_row_limit][super::MdKrokiBuilder::data_row_limit] is set.
+pub const DEFAULT_DATA_ROW_LIMIT: usize = 10_000;
+
+/// Dataset of a Vega spec that receives the rows unless a `data-name` attribute names another.
+const DEFAULT_VEGA_DATASET: &str = "table";
+
+/// Parse a data file into rows. The format is taken from the extension of `path`.
+pub(crate) fn parse_rows(path: &Path, content: &str) -> Result<Vec<Value>> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("csv") => parse_delimited(content, b','),
+        Some("tsv") => parse_delimited(content, b'\t'),
+        Some("json") => match serde_json::from_str(content)? {
+            Value::Array(rows) => Ok(rows),
+            _ => bail!("a JSON data file must contain an array of rows"),
+        },
+        _ => bail!("unsupported data file {}; expected .csv, .tsv or .json", path.display()),
+    }
+}
+
+fn parse_delimited(content: &str, delimiter: u8) -> Result<Vec<Value>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(content.as_bytes());
+    let headers = reader.headers()?.clone();
+    let records = reader.records().collect::<Result<Vec<_>, _>>()?;
+
+    let kinds: Vec<ColumnKind> = (0..headers.len())
+        .map(|column| ColumnKind::infer(records.iter().filter_map(|record| record.get(column))))
+        .collect();
+    Ok(records
+        .iter()
+        .map(|record| {
+            let row: Map<String, Value> = headers
+                .iter()
+                .zip(&kinds)
+                .zip(record.iter())
+                .map(|((header, kind), cell)| (header.to_string(), kind.value(cell)))
+                .collect();
+            Value::Object(row)
+        })
+        .collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnKind {
+    Number,
+    Boolean,
+    Text,
+}
+
+impl ColumnKind {
+    /// The narrowest kind that fits every non-empty cell. A column of only empty cells is text.
+    fn infer<'a>(cells: impl Iterator<Item = &'a str>) -> Self {
+        let mut cells = cells.map(str::trim).filter(|cell| !cell.is_empty()).peekable();
+        if cells.peek().is_none() {
+            return ColumnKind::Text;
+        }
+        let (mut number, mut boolean) = (true, true);
+        for cell in cells {
+            number &= parse_number(cell).is_some();
+            boolean &= parse_boolean(cell).is_some();
+        }
+        match (number, boolean) {
+            (true, _) => ColumnKind::Number,
+            (_, true) => ColumnKind::Boolean,
+            _ => ColumnKind::Text,
+        }
+    }
+
+    fn value(self, cell: &str) -> Value {
+        let trimmed = cell.trim();
+        match self {
+            ColumnKind::Text => Value::String(cell.to_string()),
+            _ if trimmed.is_empty() => Value::Null,
+            ColumnKind::Number => parse_number(trimmed).expect("column was inferred as numbers"),
+            ColumnKind::Boolean => Value::Bool(parse_boolean(trimmed).expect("column was inferred as booleans")),
+        }
+    }
+}
+
+/// Integers stay integers, so ids and years aren't written as `2024.0`.
+///
+/// Only plain decimals are numbers: an optional `-`, digits without leading zeros and an optional
+/// fraction. A leading zero, exponent, `+` sign or `inf` would be lost in the conversion.
+fn parse_number(cell: &str) -> Option<Value> {
+    let unsigned = cell.strip_prefix('-').unwrap_or(cell);
+    let (whole, fraction) = match unsigned.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (unsigned, None),
+    };
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit());
+    if !digits(whole) || (whole.len() > 1 && whole.starts_with('0')) || !fraction.is_none_or(digits) {
+        return None;
+    }
+    if let Ok(integer) = cell.parse::<i64>() {
+        return Some(integer.into());
+    }
+    let float = cell.parse::<f64>().ok().filter(|float| float.is_finite())?;
+    serde_json::Number::from_f64(float).map(Value::Number)
+}
+
+fn parse_boolean(cell: &str) -> Option<bool> {
+    match cell.to_ascii_lowercase().as_str() {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Put `rows` into a Vega-Lite spec's top-level `data`, or into a dataset of a Vega spec.
+///
+/// Either way the rows replace the dataset's `url` or `values`; its other properties are kept.
+/// For Vega, the dataset is the one called `dataset` (or `table`), added if the spec lacks it.
+pub(crate) fn inject_rows(
+    diagram_type: &str,
+    spec: &str,
+    rows: Vec<Value>,
+    dataset: Option<&str>,
+) -> Result<String> {
+    if !matches!(diagram_type, "vega" | "vegalite") {
+        bail!("`data` is only supported for vega and vegalite diagrams, not {diagram_type}");
+    }
+    let mut spec: Value = serde_json::from_str(spec).context("the diagram is not a JSON spec")?;
+    let object = spec
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("the diagram spec must be a JSON object"))?;
+    match diagram_type {
+        "vegalite" => {
+            if dataset.is_some() {
+                bail!("`data-name` is only supported for vega diagrams");
+            }
+            // Keep the dataset's `name` and `format`; only the rows' origin changes.
+            match object.get_mut("data").and_then(Value::as_object_mut) {
+                Some(data) => {
+                    data.remove("url");
+                    data.insert("values".to_string(), Value::Array(rows));
+                }
+                None => {
+                    object.insert("data".to_string(), json!({ "values": rows }));
+                }
+            }
+        }
+        "vega" => {
+            let name = dataset.unwrap_or(DEFAULT_VEGA_DATASET);
+            let datasets = object
+                .entry("data")
+                .or_insert_with(|| Value::Array(Vec::new()))
+                .as_array_mut()
+                .ok_or_else(|| anyhow!("`data` of a vega spec must be an array"))?;
+            let existing = datasets
+                .iter_mut()
+                .filter_map(Value::as_object_mut)
+                .find(|dataset| dataset.get("name").and_then(Value::as_str) == Some(name));
+            match existing {
+                Some(existing) => {
+                    existing.remove("url");
+                    existing.insert("values".to_string(), Value::Array(rows));
+                }
+                None => datasets.push(json!({ "name": name, "values": rows })),
+            }
+        }
+        _ => unreachable!("checked above"),
+    }
+    Ok(spec.to_string())
+}
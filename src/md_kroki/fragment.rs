@@ -0,0 +1,134 @@
+//! Selecting one named diagram out of a source file that contains several.
+//!
+//! A file reference like `flows.puml#checkout` names a fragment, which is either a PlantUML
+//! block (`@startuml checkout` ... `@enduml`) or a region between comment markers:
+//!
+//! ```text
+//! // region: checkout
+//! ...
+//! // endregion
+//! ```
+//!
+//! Any of the comment styles `//`, `#`, `'`, `--`, `%%`, `;` and `/* */` can introduce the markers,
+//! so they work in most diagram languages.
+
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+
+/// Split a `path#fragment` reference. Returns the path unchanged if there is no fragment.
+///
+/// Only a suffix made of letters, digits, `_` and `-` is a fragment, so file names like
+/// `C#-classes.puml` or `notes#2.md` stay whole. A `#` in a file name can also be written as
+/// `%23`, which is decoded in the returned path.
+pub(crate) fn split_fragment(reference: &str) -> (Cow<'_, str>, Option<&str>) {
+    let (path, fragment) = match reference.rsplit_once('#') {
+        Some((path, fragment)) if is_fragment_name(fragment) => (path, Some(fragment)),
+        _ => (reference, None),
+    };
+    (decode_hash(path), fragment)
+}
+
+/// Decode `%23` in a path to `#`.
+pub(crate) fn decode_hash(path: &str) -> Cow<'_, str> {
+    match path.contains("%23") {
+        true => Cow::Owned(path.replace("%23", "#")),
+        false => Cow::Borrowed(path),
+    }
+}
+
+fn is_fragment_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Extract the PlantUML block or region called `name` from `source`.
+pub(crate) fn extract_fragment(source: &str, name: &str) -> Result<String> {
+    if let Some(block) = plantuml_block(source, name) {
+        return Ok(block);
+    }
+    if let Some(region) = region(source, name) {
+        return Ok(region);
+    }
+    bail!("no @start block or region named \"{name}\" found")
+}
+
+/// The lines of the `@start<kind> name` block, including its start and end lines.
+fn plantuml_block(source: &str, name: &str) -> Option<String> {
+    let mut lines = source.lines();
+    let kind = loop {
+        let line = lines.next()?.trim();
+        let Some(rest) = line.strip_prefix("@start") else {
+            continue;
+        };
+        let kind_end = rest
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        let (kind, block_name) = rest.split_at(kind_end);
+        // Both `@startuml name` and `@startuml(id=name)` are valid.
+        let block_name = block_name.trim();
+        let block_name = block_name
+            .strip_prefix("(id=")
+            .and_then(|n| n.strip_suffix(')'))
+            .unwrap_or(block_name);
+        if block_name == name {
+            break (kind.to_string(), line.to_string());
+        }
+    };
+
+    let (kind, start_line) = kind;
+    let end = format!("@end{kind}");
+    let mut block = vec![start_line];
+    for line in lines {
+        block.push(line.to_string());
+        if line.trim().starts_with(&end) {
+            return Some(block.join("\n") + "\n");
+        }
+    }
+    None
+}
+
+/// The lines between the `region: name` marker and its matching `endregion`, without markers.
+fn region(source: &str, name: &str) -> Option<String> {
+    let mut lines = source.lines();
+    lines.find(|line| matches!(marker(line), Some(Marker::Start(n)) if n == name))?;
+
+    let mut depth = 0;
+    let mut region = String::new();
+    for line in lines {
+        match marker(line) {
+            Some(Marker::Start(_)) => depth += 1,
+            Some(Marker::End) if depth == 0 => return Some(region),
+            Some(Marker::End) => depth -= 1,
+            None => {
+                region.push_str(line);
+                region.push('\n');
+            }
+        }
+    }
+    None
+}
+
+enum Marker<'a> {
+    Start(&'a str),
+    End,
+}
+
+fn marker(line: &str) -> Option<Marker<'_>> {
+    let line = line.trim();
+    let comment = ["//", "/*", "#", "'", "--", "%%", ";"]
+        .iter()
+        .find_map(|prefix| line.strip_prefix(prefix))?;
+    let comment = comment.trim_end().trim_end_matches("*/").trim();
+
+    if comment.starts_with("endregion") {
+        return Some(Marker::End);
+    }
+    let name = comment.strip_prefix("region")?;
+    if !name.starts_with(|c: char| c == ':' || c.is_whitespace()) {
+        return None;
+    }
+    let name = name.trim_start_matches(':').trim();
+    (!name.is_empty()).then_some(Marker::Start(name))
+}
@@ -0,0 +1,159 @@
+//! Connection settings shared by the async and blocking HTTP clients.
+
+use anyhow::{bail, Context, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{Certificate, Identity, Proxy};
+use std::sync::OnceLock;
+
+/// Raw connection settings collected by the builder. They're validated when the clients are built.
+#[derive(Default, Clone)]
+pub(crate) struct HttpSettings {
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) bearer_token: Option<String>,
+    pub(crate) proxy: Option<String>,
+    pub(crate) root_certificates: Vec<Vec<u8>>,
+    pub(crate) identity: Option<Vec<u8>>,
+}
+
+impl HttpSettings {
+    fn headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::try_from(name.as_str())
+                .with_context(|| format!("invalid header name {name:?}"))?;
+            let value = HeaderValue::try_from(value.as_str())
+                .with_context(|| format!("invalid value for header {name}"))?;
+            headers.append(name, value);
+        }
+        if let Some(token) = &self.bearer_token {
+            let mut value = HeaderValue::try_from(format!("Bearer {token}"))
+                .context("bearer token contains invalid characters")?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        Ok(headers)
+    }
+
+    fn proxy(&self) -> Result<Option<Proxy>> {
+        self.proxy
+            .as_deref()
+            .map(|url| Proxy::all(url).with_context(|| format!("invalid proxy url {url:?}")))
+            .transpose()
+    }
+
+    fn root_certificates(&self) -> Result<Vec<Certificate>> {
+        let mut certificates = Vec::new();
+        for pem in &self.root_certificates {
+            let bundle = Certificate::from_pem_bundle(pem).context("invalid CA certificate")?;
+            if bundle.is_empty() {
+                bail!("no certificates found in CA bundle");
+            }
+            certificates.extend(bundle);
+        }
+        Ok(certificates)
+    }
+
+    fn identity(&self) -> Result<Option<Identity>> {
+        self.identity
+            .as_deref()
+            .map(|pem| Identity::from_pem(pem).context("invalid client certificate"))
+            .transpose()
+    }
+
+    pub(crate) fn client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder().default_headers(self.headers()?);
+        if let Some(proxy) = self.proxy()? {
+            builder = builder.proxy(proxy);
+        }
+        for certificate in self.root_certificates()? {
+            builder = builder.add_root_certificate(certificate);
+        }
+        if let Some(identity) = self.identity()? {
+            builder = builder.identity(identity);
+        }
+        Ok(builder.build()?)
+    }
+
+    pub(crate) fn blocking_client(&self) -> Result<reqwest::blocking::Client> {
+        let mut builder = reqwest::blocking::Client::builder().default_headers(self.headers()?);
+        if let Some(proxy) = self.proxy()? {
+            builder = builder.proxy(proxy);
+        }
+        for certificate in self.root_certificates()? {
+            builder = builder.add_root_certificate(certificate);
+        }
+        if let Some(identity) = self.identity()? {
+            builder = builder.identity(identity);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// A blocking client that is only built on first use.
+///
+/// Building a blocking client spins up its own runtime, which panics inside an async context.
+/// Deferring it means renderers can be created anywhere and only [render_sync][super::MdKroki::render_sync]
+/// callers pay for it.
+pub(crate) struct LazyBlockingClient {
+    settings: HttpSettings,
+    client: OnceLock<reqwest::blocking::Client>,
+}
+
+impl LazyBlockingClient {
+    pub(crate) fn new(settings: HttpSettings) -> Self {
+        LazyBlockingClient {
+            settings,
+            client: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn ready(client: reqwest::blocking::Client) -> Self {
+        LazyBlockingClient {
+            settings: HttpSettings::default(),
+            client: OnceLock::from(client),
+        }
+    }
+
+    pub(crate) fn get(&self) -> Result<&reqwest::blocking::Client> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let client = self
+            .settings
+            .blocking_client()
+            .context("could not build blocking http client")?;
+        Ok(self.client.get_or_init(|| client))
+    }
+}
+
+/// A failed kroki request, with the body of the error response if there was one.
+///
+/// Kroki explains rejected diagrams in the body, e.g. with the syntax error and its line.
+#[derive(Debug)]
+pub(crate) struct RequestError {
+    pub(crate) error: reqwest::Error,
+    pub(crate) body: Option<String>,
+}
+
+impl From<reqwest::Error> for RequestError {
+    fn from(error: reqwest::Error) -> Self {
+        RequestError { error, body: None }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)?;
+        match self.body.as_deref().map(str::trim) {
+            Some(body) if !body.is_empty() => write!(f, ": {body}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // The reqwest error is already part of the message; skip to its cause.
+        std::error::Error::source(&self.error)
+    }
+}
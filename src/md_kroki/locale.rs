@@ -0,0 +1,78 @@
+//! Localized diagrams: `{lang}` in file references and `{{label:key}}` in inline sources.
+//!
+//! A reference like `path="diagrams/{lang}/flow.puml"` loads the file for the configured
+//! language, or the one for the default language if that doesn't exist. Inline sources can
+//! use `{{label:key}}` to pull a string from the label table of the language.
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+/// Placeholder replaced with the language in file references.
+pub(crate) const LANG_PLACEHOLDER: &str = "{lang}";
+
+const LABEL_START: &str = "{{label:";
+const LABEL_END: &str = "}}";
+
+/// The language diagrams are rendered in, and the strings for each language.
+#[derive(Debug, Default)]
+pub(crate) struct Localization {
+    pub(crate) language: Option<String>,
+    pub(crate) default_language: Option<String>,
+    pub(crate) labels: HashMap<String, BTreeMap<String, String>>,
+}
+
+impl Localization {
+    /// Languages to try for a `{lang}` reference, in order.
+    pub(crate) fn languages(&self) -> Vec<&str> {
+        let mut languages: Vec<&str> = self.language.iter().map(String::as_str).collect();
+        if let Some(default) = &self.default_language {
+            if !languages.contains(&default.as_str()) {
+                languages.push(default);
+            }
+        }
+        languages
+    }
+
+    /// Replace every `{{label:key}}` in `source`. Sources are left alone if no labels are set.
+    pub(crate) fn substitute_labels(&self, source: String) -> Result<String> {
+        if self.labels.is_empty() || !source.contains(LABEL_START) {
+            return Ok(source);
+        }
+        let mut result = String::with_capacity(source.len());
+        let mut rest = source.as_str();
+        while let Some(start) = rest.find(LABEL_START) {
+            result.push_str(&rest[..start]);
+            let after = &rest[start + LABEL_START.len()..];
+            let end = after
+                .find(LABEL_END)
+                .ok_or_else(|| anyhow!("unclosed {LABEL_START} placeholder"))?;
+            result.push_str(self.label(after[..end].trim())?);
+            rest = &after[end + LABEL_END.len()..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    /// The string for `key` in the first of [languages][Self::languages] that has it.
+    fn label(&self, key: &str) -> Result<&str> {
+        let languages = self.languages();
+        if languages.is_empty() {
+            bail!("cannot substitute label {key:?} without a language");
+        }
+        languages
+            .iter()
+            .find_map(|language| self.labels.get(*language)?.get(key))
+            .map(String::as_str)
+            .ok_or_else(|| anyhow!("no label {key:?} for language {:?}", languages[0]))
+    }
+}
+
+/// Whether reading a file failed because it doesn't exist, as opposed to e.g. being forbidden.
+pub(crate) fn is_not_found(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+    })
+}
@@ -13,7 +13,7 @@
 //! You can create a default renderer easily:
 //!
 //! ```rust
-//! # use md_kroki::MdKroki;
+//! # use boilerplate::md_kroki::MdKroki;
 //! # tokio_test::block_on(async {
 //! # let my_markdown_string: String = String::new();
 //! // This default renderer uses the kroki.io API and only allows inlined diagrams.
@@ -28,7 +28,7 @@
 //! You can configure the endpoint and enable external file references with the builder:
 //!
 //! ```rust
-//! # use md_kroki::MdKroki;
+//! # use boilerplate::md_kroki::MdKroki;
 //! # tokio_test::block_on(async {
 //! # let my_markdown_string: String = String::new();
 //! let renderer = MdKroki::builder()
@@ -102,22 +102,111 @@
 //! like a normal image tag.
 //!
 //! You must provide a path resolver to the builder if you want to use file references.
+//!
+//! A file that holds several diagrams can be referenced with a `#name` fragment, like
+//! `path="flows.puml#checkout"`. It selects the PlantUML block started by `@startuml checkout`,
+//! or the lines between `// region: checkout` and `// endregion` comments.
+//!
+//! ## Generated diagrams
+//!
+//! A `<kroki>` tag can take its source from a [source generator][MdKrokiBuilder::source_generator]
+//! instead, selected by name:
+//!
+//! ```md
+//! <kroki type="graphviz" generate="deps-graph" />
+//! ```
+//!
+//! ## Data files
+//!
+//! Vega and Vega-Lite specs can take their data from a CSV, TSV or JSON file, which is read
+//! through the path resolver and merged into the spec as inline `values`:
+//!
+//! ```md
+//! <kroki type="vegalite" path="latency.json" data="metrics.csv" />
+//! ```
+//!
+//! For Vega specs, the rows go into the dataset named by a `data-name` attribute, or `table`.
+//!
+//! ## Localized diagrams
+//!
+//! With a [language][MdKrokiBuilder::language] set, `{lang}` in a file reference is replaced with
+//! it, like `path="diagrams/{lang}/flow.puml"`. If that file doesn't exist, the
+//! [default language][MdKrokiBuilder::default_language] is tried instead. Inline sources can use
+//! `{{label:key}}` placeholders, which are replaced with strings from the
+//! [label tables][MdKrokiBuilder::labels] of those languages.
+//!
+//! ## Caching and offline rendering
+//!
+//! Renders can be stored in a [RenderCache] directory so unchanged diagrams aren't sent to kroki again.
+//! With [offline][MdKrokiBuilder::offline] mode enabled, no network requests are made at all: every
+//! diagram must be in the cache, and rendering fails with a list of the missing ones otherwise.
+//!
+//! ## Extracting diagrams
+//!
+//! Tools that only need to find diagrams, like linters or galleries, can use [MdKroki::extract].
+//! It runs the same parser as rendering and returns a [DiagramSpec] for each diagram, with its
+//! location in the markdown, syntax, type, attributes and source:
+//!
+//! ```rust
+//! # use boilerplate::md_kroki::MdKroki;
+//! let specs = MdKroki::new().extract("```kroki-dot\ndigraph {}\n```\n")?;
+//! assert_eq!(specs[0].diagram_type, "dot");
+//! assert_eq!(specs[0].lines, 1..=3);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
 
 #![deny(missing_docs)]
 
+mod cache;
+mod data;
+mod fragment;
+mod http;
+mod locale;
+mod optimize;
+pub mod postprocess;
 mod render;
+mod routing;
+mod spec;
+mod types;
 #[cfg(test)]
 mod test;
 
-use anyhow::Result;
-use std::path::PathBuf;
+use anyhow::{Context, Result};
+use http::{HttpSettings, LazyBlockingClient};
+use locale::Localization;
+use postprocess::PostProcessor;
+use types::normalize_extension;
+use routing::Endpoints;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use cache::{CacheMiss, RenderCache};
+pub use data::DEFAULT_DATA_ROW_LIMIT;
+pub use postprocess::RenderedOutput;
+pub use spec::{DiagramKind, DiagramSpec};
+pub use render::{escape_html, SourceLine};
 
 /// Kroki diagram renderer.
+///
+/// Cloning is cheap: clones share the HTTP clients (and their connection pools) and the path resolver.
+#[derive(Clone)]
 pub struct MdKroki {
-    endpoint: String,
-    path_resolver: PathResolver,
+    endpoints: Arc<Endpoints>,
+    path_resolver: Arc<PathResolver>,
     client: reqwest::Client,
-    blocking_client: reqwest::blocking::Client,
+    blocking_client: Arc<LazyBlockingClient>,
+    cache: Option<RenderCache>,
+    offline: bool,
+    post_processors: Arc<Vec<PostProcessor>>,
+    source_generator: Option<Arc<SourceGenerator>>,
+    render_observer: Option<Arc<RenderObserver>>,
+    fetch_observer: Option<Arc<FetchObserver>>,
+    data_row_limit: usize,
+    extensions: Arc<HashMap<String, String>>,
+    localization: Arc<Localization>,
+    document_path: Option<PathBuf>,
 }
 
 impl MdKroki {
@@ -130,8 +219,37 @@ impl MdKroki {
     pub fn builder() -> MdKrokiBuilder {
         MdKrokiBuilder::new()
     }
+
+    /// A renderer for the markdown document at `path`, sharing this renderer's clients.
+    ///
+    /// The path is passed to a [document_path_resolver][MdKrokiBuilder::document_path_resolver],
+    /// so relative file references can be resolved against the document.
+    pub fn for_document(&self, path: Option<PathBuf>) -> MdKroki {
+        MdKroki {
+            document_path: path,
+            ..self.clone()
+        }
+    }
+}
+
+/// Identifies a document passed to [MdKroki::render_many].
+pub trait DocumentId {
+    /// Path of the document, passed to a [document_path_resolver][MdKrokiBuilder::document_path_resolver].
+    fn document_path(&self) -> Option<&Path> {
+        None
+    }
 }
 
+impl DocumentId for PathBuf {
+    fn document_path(&self) -> Option<&Path> {
+        Some(self)
+    }
+}
+
+impl DocumentId for String {}
+
+impl DocumentId for usize {}
+
 /// Options for resolving paths in tags that reference external files.
 ///
 /// It will cause an error if you use a path without providing an appropriate resolver.
@@ -140,16 +258,54 @@ impl MdKroki {
 enum PathResolver {
     #[default]
     None,
-    Path(Box<dyn Fn(PathBuf) -> Result<String> + Send>),
-    PathAndRoot(Box<dyn Fn(PathBuf, Option<&str>) -> Result<String> + Send>),
+    Path(Box<dyn Fn(PathBuf) -> Result<String> + Send + Sync>),
+    PathAndRoot(Box<dyn Fn(PathBuf, Option<&str>) -> Result<String> + Send + Sync>),
+    Document(Box<dyn Fn(PathBuf, Option<&str>, Option<&Path>) -> Result<String> + Send + Sync>),
+    DocumentFile(Box<dyn Fn(PathBuf, Option<&str>, Option<&Path>) -> Result<(PathBuf, String)> + Send + Sync>),
+}
+
+type SourceGenerator = dyn Fn(&str) -> Result<String> + Send + Sync;
+
+type RenderObserver = dyn Fn(Option<&Path>, &DiagramSpec, &str) + Send + Sync;
+
+type FetchObserver = dyn Fn(&FetchRecord) + Send + Sync;
+
+/// How kroki's response for one diagram was obtained, passed to an
+/// [on_fetch][MdKrokiBuilder::on_fetch] observer.
+#[derive(Debug)]
+pub struct FetchRecord<'a> {
+    /// Path of the document the diagram was taken from, if known.
+    pub document: Option<&'a Path>,
+    /// The diagram.
+    pub spec: &'a DiagramSpec,
+    /// The endpoint that rendered the diagram; `None` for cache hits and failures.
+    pub endpoint: Option<&'a str>,
+    /// Whether the response came from the cache.
+    pub cached: bool,
+    /// Time spent getting the response, including requests to endpoints that were fallen back from.
+    pub elapsed: Duration,
+    /// Why the diagram could not be rendered.
+    pub error: Option<&'a anyhow::Error>,
 }
 
 /// Builder for configuring the renderer.
 pub struct MdKrokiBuilder {
     endpoint: String,
+    routes: HashMap<String, String>,
+    fallbacks: Vec<String>,
     path_resolver: PathResolver,
-    client: reqwest::Client,
-    blocking_client: reqwest::blocking::Client,
+    client: Option<reqwest::Client>,
+    blocking_client: Option<reqwest::blocking::Client>,
+    http: HttpSettings,
+    cache: Option<RenderCache>,
+    offline: bool,
+    post_processors: Vec<PostProcessor>,
+    source_generator: Option<Arc<SourceGenerator>>,
+    render_observer: Option<Arc<RenderObserver>>,
+    fetch_observer: Option<Arc<FetchObserver>>,
+    data_row_limit: usize,
+    extensions: HashMap<String, String>,
+    localization: Localization,
 }
 
 impl MdKrokiBuilder {
@@ -166,11 +322,25 @@ impl MdKrokiBuilder {
         self
     }
 
+    /// Send diagrams of one type to a different endpoint than the default, e.g. when a local
+    /// deployment lacks the companion container for that type.
+    pub fn route(mut self, diagram_type: impl Into<String>, endpoint: impl std::fmt::Display) -> Self {
+        self.routes.insert(diagram_type.into(), endpoint.to_string());
+        self
+    }
+
+    /// Add an endpoint to try when the endpoint of a diagram fails with a connection error or
+    /// a 5xx response. Fallbacks are tried in the order they were added.
+    pub fn fallback_endpoint(mut self, endpoint: impl std::fmt::Display) -> Self {
+        self.fallbacks.push(endpoint.to_string());
+        self
+    }
+
     /// Sets a basic path resolver. Unnecessary if all your diagrams are inline. Example:
     ///
     /// ```
     /// # use std::path::Path;
-    /// # use md_kroki::MdKroki;
+    /// # use boilerplate::md_kroki::MdKroki;
     /// let resolver = |path| {
     ///     let base_path = Path::new("path/to/files");
     ///     Ok(std::fs::read_to_string(base_path.join(path))?)
@@ -181,7 +351,7 @@ impl MdKrokiBuilder {
     /// ```
     pub fn path_resolver<F>(mut self, path_resolver: F) -> Self
     where
-        F: Fn(PathBuf) -> Result<String> + Send + 'static,
+        F: Fn(PathBuf) -> Result<String> + Send + Sync + 'static,
     {
         self.path_resolver = PathResolver::Path(Box::new(path_resolver));
         self
@@ -192,18 +362,20 @@ impl MdKrokiBuilder {
     /// If none of your diagrams use a root attribute, just use [path_resolver][Self::path_resolver].
     /// There is no need to provide both [path_resolver][Self::path_resolver] and [path_and_root_resolver][Self::path_and_root_resolver].
     ///
-    /// This option is only available on external file references on the
-    /// `<kroki>` tag. Using the `root` attribute will send that value to the resolver:
+    /// Using the `root` attribute on the `<kroki>` tag will send that value to the resolver:
     ///
     /// ```xml
     /// <kroki type="mermaid" path="file.mermaid" root="assets" />
     /// ```
     ///
+    /// Image references carry a root with either `kroki-mermaid:assets:file.mermaid` or
+    /// `kroki-mermaid://assets/file.mermaid`.
+    ///
     /// In most cases this option will be unnecessary. Example:
     ///
     /// ```
     /// # use std::path::Path;
-    /// # use md_kroki::MdKroki;
+    /// # use boilerplate::md_kroki::MdKroki;
     /// # use anyhow::bail;
     /// let resolver = |path, root: Option<&str>| {
     ///     let base_path = match root {
@@ -222,42 +394,299 @@ impl MdKrokiBuilder {
     /// type of the `root` argument. It can't be inferred.
     pub fn path_and_root_resolver<F>(mut self, path_resolver: F) -> Self
     where
-        F: Fn(PathBuf, Option<&str>) -> Result<String> + Send + 'static,
+        F: Fn(PathBuf, Option<&str>) -> Result<String> + Send + Sync + 'static,
     {
         let wrapped = move |path, root: Option<&str>| path_resolver(path, root);
         self.path_resolver = PathResolver::PathAndRoot(Box::new(wrapped));
         self
     }
 
+    /// Path resolver that also receives the path of the document being rendered.
+    ///
+    /// Use this to share one renderer between many documents: build it once, then call
+    /// [for_document][MdKroki::for_document] for each document. The document path is `None`
+    /// unless one was given. Example:
+    ///
+    /// ```
+    /// # use std::path::Path;
+    /// # use boilerplate::md_kroki::MdKroki;
+    /// let resolver = |path, _root: Option<&str>, document: Option<&Path>| {
+    ///     let base_path = document.and_then(Path::parent).unwrap_or(Path::new(""));
+    ///     Ok(std::fs::read_to_string(base_path.join(path))?)
+    /// };
+    /// let md_kroki = MdKroki::builder()
+    ///     .document_path_resolver(resolver)
+    ///     .build();
+    /// let chapter_renderer = md_kroki.for_document(Some("chapters/intro.md".into()));
+    /// ```
+    pub fn document_path_resolver<F>(mut self, path_resolver: F) -> Self
+    where
+        F: Fn(PathBuf, Option<&str>, Option<&Path>) -> Result<String> + Send + Sync + 'static,
+    {
+        let wrapped =
+            move |path, root: Option<&str>, document: Option<&Path>| path_resolver(path, root, document);
+        self.path_resolver = PathResolver::Document(Box::new(wrapped));
+        self
+    }
+
+    /// Like [document_path_resolver][Self::document_path_resolver], but the resolver also returns
+    /// the path it read the file from, which becomes the [path][DiagramSpec::path] of the spec.
+    /// Example:
+    ///
+    /// ```
+    /// # use std::path::Path;
+    /// # use boilerplate::md_kroki::MdKroki;
+    /// let md_kroki = MdKroki::builder()
+    ///     .document_file_resolver(|path, _root: Option<&str>, document: Option<&Path>| {
+    ///         let file = document.and_then(Path::parent).unwrap_or(Path::new("")).join(path);
+    ///         let source = std::fs::read_to_string(&file)?;
+    ///         Ok((file, source))
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn document_file_resolver<F>(mut self, path_resolver: F) -> Self
+    where
+        F: Fn(PathBuf, Option<&str>, Option<&Path>) -> Result<(PathBuf, String)> + Send + Sync + 'static,
+    {
+        let wrapped =
+            move |path, root: Option<&str>, document: Option<&Path>| path_resolver(path, root, document);
+        self.path_resolver = PathResolver::DocumentFile(Box::new(wrapped));
+        self
+    }
+
+    /// Produces diagram sources for `<kroki>` tags with a `generate` attribute.
+    ///
+    /// The generator receives the attribute value, a name, and returns the diagram source.
+    /// Without a generator, tags with a `generate` attribute fail to render. Example:
+    ///
+    /// ```
+    /// # use boilerplate::md_kroki::MdKroki;
+    /// # use anyhow::bail;
+    /// let md_kroki = MdKroki::builder()
+    ///     .source_generator(|name| match name {
+    ///         "hello" => Ok("digraph { hello -> world }".to_string()),
+    ///         _ => bail!("unknown generator {name}"),
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn source_generator<F>(mut self, generator: F) -> Self
+    where
+        F: Fn(&str) -> Result<String> + Send + Sync + 'static,
+    {
+        self.source_generator = Some(Arc::new(generator));
+        self
+    }
+
+    /// Call `observer` for every diagram inlined into a document, with the document path (see
+    /// [for_document][MdKroki::for_document]), the diagram and the html that replaces it.
+    ///
+    /// It is called for every occurrence, also when [render_many][MdKroki::render_many] shared
+    /// one render between documents, but not for diagrams that failed. Example:
+    ///
+    /// ```
+    /// # use boilerplate::md_kroki::MdKroki;
+    /// let md_kroki = MdKroki::builder()
+    ///     .on_render(|document, spec, html| {
+    ///         println!("{document:?}:{}: {} bytes", spec.lines.start(), html.len());
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn on_render<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(Option<&Path>, &DiagramSpec, &str) + Send + Sync + 'static,
+    {
+        self.render_observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Call `observer` each time kroki's response for a diagram is fetched or read from the cache,
+    /// with the time it took and whether it failed.
+    ///
+    /// A diagram shared between documents by [render_many][MdKroki::render_many] is fetched, and
+    /// reported, once. Example:
+    ///
+    /// ```
+    /// # use boilerplate::md_kroki::MdKroki;
+    /// let md_kroki = MdKroki::builder()
+    ///     .on_fetch(|fetch| {
+    ///         println!("{} diagram took {:?}", fetch.spec.diagram_type, fetch.elapsed);
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn on_fetch<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(&FetchRecord) + Send + Sync + 'static,
+    {
+        self.fetch_observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Keep at most `limit` rows of a data file merged into a diagram with a `data` attribute.
+    ///
+    /// Extra rows are dropped with a warning. The default is [DEFAULT_DATA_ROW_LIMIT].
+    pub fn data_row_limit(mut self, limit: usize) -> Self {
+        self.data_row_limit = limit;
+        self
+    }
+
+    /// Use a preconfigured client for [render][MdKroki::render].
+    ///
+    /// The connection settings of this builder (headers, proxy, certificates) are not applied to it.
     pub fn client(mut self, client: reqwest::Client) -> Self {
-        self.client = client;
+        self.client = Some(client);
         self
     }
 
+    /// Use a preconfigured client for [render_sync][MdKroki::render_sync].
+    ///
+    /// The connection settings of this builder (headers, proxy, certificates) are not applied to it.
     pub fn blocking_client(mut self, client: reqwest::blocking::Client) -> Self {
-        self.blocking_client = client;
+        self.blocking_client = Some(client);
+        self
+    }
+
+    /// Send a static header with every request to kroki.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.http.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Authenticate every request with an `Authorization: Bearer <token>` header.
+    pub fn bearer_token(mut self, token: impl Into<String>) -> Self {
+        self.http.bearer_token = Some(token.into());
+        self
+    }
+
+    /// Send all requests through an HTTP(S) proxy, e.g. `http://proxy.internal:3128`.
+    pub fn proxy(mut self, url: impl Into<String>) -> Self {
+        self.http.proxy = Some(url.into());
+        self
+    }
+
+    /// Trust additional PEM-encoded CA certificates, for deployments with a private CA.
+    /// `pem` may be a bundle of several certificates.
+    pub fn ca_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.http.root_certificates.push(pem.into());
+        self
+    }
+
+    /// Present a client certificate. `pem` must contain both the certificate chain and its private key.
+    pub fn client_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.http.identity = Some(pem.into());
+        self
+    }
+
+    /// Store renders in a cache directory and reuse them for identical diagrams.
+    pub fn cache(mut self, cache: RenderCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Forbid all network access and serve every diagram from the [cache][Self::cache].
+    ///
+    /// Rendering fails with a [CacheMiss] for each diagram that isn't cached.
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Treat files with `extension` as diagrams of `diagram_type` when a reference doesn't name
+    /// the type. Overrides the built-in table, which covers common extensions like `.puml`,
+    /// `.dot` and `.mmd`. The extension may be given with or without its leading dot.
+    pub fn extension(mut self, extension: &str, diagram_type: impl Into<String>) -> Self {
+        self.extensions.insert(normalize_extension(extension), diagram_type.into());
+        self
+    }
+
+    /// Set the language that `{lang}` in file references is replaced with.
+    pub fn language(mut self, language: impl Into<String>) -> Self {
+        self.localization.language = Some(language.into());
+        self
+    }
+
+    /// Set the language used when a `{lang}` file doesn't exist for the [language][Self::language],
+    /// or a label isn't translated into it.
+    pub fn default_language(mut self, language: impl Into<String>) -> Self {
+        self.localization.default_language = Some(language.into());
+        self
+    }
+
+    /// Add strings for `{{label:key}}` placeholders in inline sources, in `language`.
+    ///
+    /// Placeholders are only substituted once some labels are set.
+    pub fn labels(mut self, language: impl Into<String>, labels: BTreeMap<String, String>) -> Self {
+        self.localization.labels.entry(language.into()).or_default().extend(labels);
+        self
+    }
+
+    /// Adjust every rendered diagram before it is inlined.
+    ///
+    /// Post-processors run in the order they were added, on cached renders too. The
+    /// [postprocess] module has built-in ones. Example:
+    ///
+    /// ```
+    /// # use boilerplate::md_kroki::MdKroki;
+    /// let md_kroki = MdKroki::builder()
+    ///     .post_process(|spec, mut output| {
+    ///         output.classes.push(format!("diagram-{}", spec.diagram_type));
+    ///         Ok(output)
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn post_process<F>(mut self, post_processor: F) -> Self
+    where
+        F: Fn(&DiagramSpec, RenderedOutput) -> Result<RenderedOutput> + Send + Sync + 'static,
+    {
+        self.post_processors.push(Box::new(post_processor));
         self
     }
 
     /// Consume self and build a renderer.
+    ///
+    /// Panics if the connection settings are invalid. Use [try_build][Self::try_build] to handle that.
     pub fn build(self) -> MdKroki {
-        MdKroki {
-            endpoint: self.endpoint,
-            path_resolver: self.path_resolver,
-            client: self.client,
-            blocking_client: self.blocking_client,
-        }
+        self.try_build().expect("could not build kroki http client")
+    }
+
+    /// Consume self and build a renderer, failing if the connection settings are invalid.
+    ///
+    /// The blocking client used by [render_sync][MdKroki::render_sync] is built on first use,
+    /// so it is safe to build renderers inside an async runtime.
+    pub fn try_build(self) -> Result<MdKroki> {
+        let client = match self.client {
+            Some(client) => client,
+            None => self.http.client().context("could not build http client")?,
+        };
+        let blocking_client = match self.blocking_client {
+            Some(client) => LazyBlockingClient::ready(client),
+            None => LazyBlockingClient::new(self.http),
+        };
+        Ok(MdKroki {
+            endpoints: Arc::new(Endpoints {
+                default: self.endpoint,
+                routes: self.routes,
+                fallbacks: self.fallbacks,
+            }),
+            path_resolver: Arc::new(self.path_resolver),
+            client,
+            blocking_client: Arc::new(blocking_client),
+            cache: self.cache,
+            offline: self.offline,
+            post_processors: Arc::new(self.post_processors),
+            source_generator: self.source_generator,
+            render_observer: self.render_observer,
+            fetch_observer: self.fetch_observer,
+            data_row_limit: self.data_row_limit,
+            extensions: Arc::new(self.extensions),
+            localization: Arc::new(self.localization),
+            document_path: None,
+        })
     }
 }
 
 impl Default for MdKroki {
     fn default() -> Self {
-        MdKroki {
-            endpoint: "https://kroki.io".to_string(),
-            path_resolver: PathResolver::None,
-            client: reqwest::Client::new(),
-            blocking_client: reqwest::blocking::Client::new(),
-        }
+        MdKrokiBuilder::default().build()
     }
 }
 
@@ -265,9 +694,21 @@ impl Default for MdKrokiBuilder {
     fn default() -> Self {
         MdKrokiBuilder {
             endpoint: "https://kroki.io".to_string(),
+            routes: HashMap::new(),
+            fallbacks: Vec::new(),
             path_resolver: PathResolver::None,
-            client: reqwest::Client::new(),
-            blocking_client: reqwest::blocking::Client::new(),
+            client: None,
+            blocking_client: None,
+            http: HttpSettings::default(),
+            cache: None,
+            offline: false,
+            post_processors: Vec::new(),
+            source_generator: None,
+            render_observer: None,
+            fetch_observer: None,
+            data_row_limit: DEFAULT_DATA_ROW_LIMIT,
+            extensions: types::default_extensions(),
+            localization: Localization::default(),
         }
     }
 }
@@ -0,0 +1,241 @@
+//! A conservative, string-level SVG minifier for kroki output.
+//!
+//! It doesn't build a DOM; it walks the markup tag by tag, so anything it doesn't recognize is
+//! copied unchanged.
+
+/// Attributes holding coordinates and lengths, the only ones whose numbers are rounded.
+///
+/// Anything else, like ids, `data-*` values, version strings or dash patterns, is copied as is.
+const GEOMETRIC_ATTRIBUTES: &[&str] = &[
+    "d",
+    "points",
+    "x",
+    "y",
+    "x1",
+    "y1",
+    "x2",
+    "y2",
+    "cx",
+    "cy",
+    "r",
+    "rx",
+    "ry",
+    "width",
+    "height",
+    "transform",
+    "viewBox",
+    "stroke-width",
+    "font-size",
+];
+
+/// Elements whose whitespace-only text is content, like the space between two `<tspan>` runs.
+const TEXT_ELEMENTS: &[&str] = &["text", "tspan", "textPath"];
+
+/// Elements where `x="0"` and `y="0"` are the default position. Text positions lines with them,
+/// and filter, mask, pattern and clipPath regions default to `-10%`, so those keep theirs.
+const POSITIONED_ELEMENTS: &[&str] = &["rect", "use", "image", "svg"];
+
+/// Shapes and groups, where an opacity of 1 and an identity transform change nothing.
+const GRAPHICS_ELEMENTS: &[&str] = &[
+    "g", "path", "rect", "circle", "ellipse", "line", "polyline", "polygon", "use", "image",
+];
+
+/// Attribute values that are the default and not inherited on the listed elements, so dropping
+/// them changes nothing. Attributes on any other element are kept.
+const DEFAULT_ATTRIBUTES: &[(&[&str], &str, &str)] = &[
+    (POSITIONED_ELEMENTS, "x", "0"),
+    (POSITIONED_ELEMENTS, "y", "0"),
+    (GRAPHICS_ELEMENTS, "opacity", "1"),
+    (GRAPHICS_ELEMENTS, "transform", "translate(0,0)"),
+    (GRAPHICS_ELEMENTS, "transform", "translate(0 0)"),
+];
+
+/// Minify an svg element.
+///
+/// Removes comments, processing instructions and `<metadata>`, drops whitespace-only text
+/// between tags and attributes that repeat their default on elements known to have it, and rounds decimals in geometric attribute values
+/// to `precision` fractional digits. Whitespace inside text elements and `xml:space="preserve"`
+/// is kept.
+pub(crate) fn optimize_svg(svg: &str, precision: usize) -> String {
+    let mut out = String::with_capacity(svg.len());
+    // For each open element, whether whitespace-only text in it is kept.
+    let mut keep_space = Vec::new();
+    let mut rest = svg;
+    while !rest.is_empty() {
+        let keep = keep_space.last() == Some(&true);
+        let Some(tag_start) = rest.find('<') else {
+            push_text(&mut out, rest, keep);
+            break;
+        };
+        push_text(&mut out, &rest[..tag_start], keep);
+        rest = &rest[tag_start..];
+
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = after.find("-->").map_or("", |end| &after[end + 3..]);
+        } else if rest.starts_with("<?") {
+            rest = rest.find("?>").map_or("", |end| &rest[end + 2..]);
+        } else if rest.starts_with("<![CDATA[") {
+            let end = rest.find("]]>").map_or(rest.len(), |end| end + 3);
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if rest.starts_with("<metadata") {
+            rest = match (rest.find("/>"), rest.find('>')) {
+                (Some(close), Some(end)) if close + 1 == end => &rest[end + 1..],
+                _ => rest.find("</metadata>").map_or("", |end| &rest[end + 11..]),
+            };
+        } else {
+            let Some(end) = tag_end(rest) else {
+                out.push_str(rest);
+                break;
+            };
+            push_tag(&mut out, &rest[..end], precision, &mut keep_space);
+            rest = &rest[end..];
+        }
+    }
+    out
+}
+
+fn push_text(out: &mut String, text: &str, keep_space: bool) {
+    if keep_space || !text.trim().is_empty() {
+        out.push_str(text);
+    }
+}
+
+/// Byte position just after the `>` that closes the tag at the start of `markup`.
+fn tag_end(markup: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in markup.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (None, '>') => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Rewrite an element tag with single spaces between attributes, tracking in `keep_space`
+/// which open elements keep their whitespace.
+fn push_tag(out: &mut String, tag: &str, precision: usize, keep_space: &mut Vec<bool>) {
+    if tag.starts_with("</") {
+        keep_space.pop();
+        out.push_str(tag);
+        return;
+    }
+    if tag.starts_with("<!") {
+        out.push_str(tag);
+        return;
+    }
+    let self_closing = tag.ends_with("/>");
+    let inner = tag[1..tag.len() - if self_closing { 2 } else { 1 }].trim();
+    let name_end = inner.find(char::is_whitespace).unwrap_or(inner.len());
+    let element = &inner[..name_end];
+    let mut keep = keep_space.last() == Some(&true) || TEXT_ELEMENTS.contains(&element);
+    out.push('<');
+    out.push_str(element);
+
+    let mut attributes = inner[name_end..].trim_start();
+    while let Some(eq) = attributes.find('=') {
+        let name = attributes[..eq].trim();
+        let value_part = attributes[eq + 1..].trim_start();
+        let Some(quote) = value_part.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            // Not well-formed; keep the remainder untouched.
+            out.push(' ');
+            out.push_str(attributes);
+            attributes = "";
+            break;
+        };
+        let Some(value_len) = value_part[1..].find(quote) else {
+            out.push(' ');
+            out.push_str(attributes);
+            attributes = "";
+            break;
+        };
+        let value = &value_part[1..1 + value_len];
+        attributes = value_part[value_len + 2..].trim_start();
+
+        if name == "xml:space" {
+            keep = value == "preserve";
+        }
+        let value = if GEOMETRIC_ATTRIBUTES.contains(&name) {
+            round_numbers(value, precision)
+        } else {
+            value.to_string()
+        };
+        if is_default(element, name, &value) {
+            continue;
+        }
+        out.push_str(&format!(" {name}={quote}{value}{quote}"));
+    }
+    if !attributes.is_empty() {
+        out.push(' ');
+        out.push_str(attributes);
+    }
+    out.push_str(if self_closing { "/>" } else { ">" });
+    if !self_closing {
+        keep_space.push(keep);
+    }
+}
+
+fn is_default(element: &str, name: &str, value: &str) -> bool {
+    DEFAULT_ATTRIBUTES
+        .iter()
+        .any(|(elements, default_name, default_value)| {
+            *default_name == name && *default_value == value && elements.contains(&element)
+        })
+}
+
+/// Round every decimal number in `value` to `precision` fractional digits.
+fn round_numbers(value: &str, precision: usize) -> String {
+    let bytes = value.as_bytes();
+    let is_digit = |i: usize| bytes.get(i).is_some_and(u8::is_ascii_digit);
+    let mut out = String::with_capacity(value.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if !(is_digit(i) || (bytes[i] == b'.' && is_digit(i + 1))) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while is_digit(i) {
+            i += 1;
+        }
+        if !(bytes.get(i) == Some(&b'.') && is_digit(i + 1)) {
+            continue;
+        }
+        i += 1;
+        while is_digit(i) {
+            i += 1;
+        }
+        if matches!(bytes.get(i), Some(b'e' | b'E')) {
+            // Leave numbers with exponents alone.
+            continue;
+        }
+
+        out.push_str(&value[copied..start]);
+        // Path data can pack numbers like "1.5.5"; once rounded, they need a separator.
+        if out.ends_with(|c: char| c.is_ascii_digit() || c == '.') {
+            out.push(' ');
+        }
+        let number = value[start..i].parse().expect("digits and a dot parse");
+        out.push_str(&format_number(number, precision));
+        copied = i;
+    }
+    out.push_str(&value[copied..]);
+    out
+}
+
+fn format_number(number: f64, precision: usize) -> String {
+    let formatted = format!("{number:.precision$}");
+    let formatted = if formatted.contains('.') {
+        formatted.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        &formatted
+    };
+    match formatted {
+        "-0" | "" => "0".to_string(),
+        _ => formatted.to_string(),
+    }
+}
@@ -0,0 +1,105 @@
+//! Hooks that adjust rendered diagrams before they are inlined, and a few built-in ones.
+
+use crate::md_kroki::optimize::optimize_svg;
+use crate::md_kroki::DiagramSpec;
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+/// A rendered diagram before it is wrapped in its `<pre>` element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedOutput {
+    /// The `<svg>` element returned by kroki.
+    pub svg: String,
+    /// Classes of the wrapping element. Starts with `diagram-kroki`.
+    pub classes: Vec<String>,
+    /// Extra attributes of the wrapping element, in order. Values are escaped when written.
+    pub attributes: Vec<(String, String)>,
+}
+
+pub(crate) type PostProcessor =
+    Box<dyn Fn(&DiagramSpec, RenderedOutput) -> Result<RenderedOutput> + Send + Sync>;
+
+/// Shrink the svg markup by removing comments, metadata, whitespace between tags and
+/// default-valued attributes, and by rounding decimals to `precision` fractional digits.
+///
+/// The bytes saved are logged for each diagram at debug level.
+pub fn optimize(
+    precision: usize,
+) -> impl Fn(&DiagramSpec, RenderedOutput) -> Result<RenderedOutput> + Send + Sync {
+    move |spec, mut output| {
+        let before = output.svg.len();
+        output.svg = optimize_svg(&output.svg, precision);
+        let after = output.svg.len();
+        log::debug!(
+            "optimized {} diagram at line {}: {before} -> {after} bytes ({} saved)",
+            spec.diagram_type,
+            spec.lines.start(),
+            before.saturating_sub(after)
+        );
+        Ok(output)
+    }
+}
+
+/// Replace colors in the svg, e.g. to match a brand palette.
+///
+/// Keys are matched case-insensitively and only as whole values, so `#fff` doesn't touch `#ffffff`.
+pub fn replace_colors(
+    colors: BTreeMap<String, String>,
+) -> impl Fn(&DiagramSpec, RenderedOutput) -> Result<RenderedOutput> + Send + Sync {
+    move |_, mut output| {
+        for (from, to) in &colors {
+            output.svg = replace_whole_ignore_case(&output.svg, from, to);
+        }
+        Ok(output)
+    }
+}
+
+/// Add classes to the element wrapping every diagram.
+pub fn add_classes(
+    classes: Vec<String>,
+) -> impl Fn(&DiagramSpec, RenderedOutput) -> Result<RenderedOutput> + Send + Sync {
+    move |_, mut output| {
+        output.classes.extend(classes.iter().cloned());
+        Ok(output)
+    }
+}
+
+/// Add `data-<name>` attributes to the element wrapping every diagram.
+///
+/// `{type}` and `{line}` in a value are replaced with the diagram type and its first line.
+pub fn add_data_attributes(
+    attributes: BTreeMap<String, String>,
+) -> impl Fn(&DiagramSpec, RenderedOutput) -> Result<RenderedOutput> + Send + Sync {
+    move |spec, mut output| {
+        for (name, value) in &attributes {
+            let value = value
+                .replace("{type}", &spec.diagram_type)
+                .replace("{line}", &spec.lines.start().to_string());
+            output.attributes.push((format!("data-{name}"), value));
+        }
+        Ok(output)
+    }
+}
+
+fn replace_whole_ignore_case(text: &str, from: &str, to: &str) -> String {
+    if from.is_empty() {
+        return text.to_string();
+    }
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    let lower = text.to_ascii_lowercase();
+    let from = from.to_ascii_lowercase();
+
+    let mut result = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (start, _) in lower.match_indices(&from) {
+        let end = start + from.len();
+        if start < copied || is_word(text[..start].chars().next_back()) || is_word(text[end..].chars().next()) {
+            continue;
+        }
+        result.push_str(&text[copied..start]);
+        result.push_str(to);
+        copied = end;
+    }
+    result.push_str(&text[copied..]);
+    result
+}
@@ -1,11 +1,27 @@
-use crate::md_kroki::{MdKroki, PathResolver};
+use crate::md_kroki::data::{inject_rows, parse_rows};
+use crate::md_kroki::fragment::{decode_hash, extract_fragment, split_fragment};
+use crate::md_kroki::http::RequestError;
+use crate::md_kroki::locale::{is_not_found, LANG_PLACEHOLDER};
+use crate::md_kroki::postprocess::PostProcessor;
+use crate::md_kroki::routing::should_fall_back;
+use crate::md_kroki::spec::{info_attributes, lines};
+use crate::md_kroki::types::normalize_extension;
+use crate::md_kroki::{
+    CacheMiss, DiagramKind, DiagramSpec, DocumentId, FetchRecord, MdKroki, PathResolver, RenderCache,
+    RenderedOutput,
+};
 use anyhow::anyhow;
-use anyhow::{bail, Result};
+use futures::FutureExt;
+use anyhow::{bail, Context, Result};
 use pulldown_cmark::{CodeBlockKind, Event, LinkType, Options, Parser, Tag};
 use serde::Serialize;
 use sscanf::sscanf;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
 use std::ops::Range;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use xmltree::Element;
 
 impl MdKroki {
@@ -13,38 +29,167 @@ impl MdKroki {
     ///
     /// Diagram render requests are awaited in parallel.
     pub async fn render(&self, mut content: String) -> Result<String> {
-        let renders = self.get_render_requests(&content)?;
-
-        let replace_futures = renders.map(|render| async {
-            let result = self.client
-                .post(&self.endpoint)
-                .body(serde_json::to_string(&render).expect("could no serialize kroki request"))
-                .send()
-                .await
-                .expect("could not send kroki request")
-                .error_for_status()?
-                .text()
-                .await?;
-            let result = process_xml(result)?;
-            Ok::<ReplaceRequest, anyhow::Error>(ReplaceRequest {
-                range: render.replace_range,
-                content: result,
-            })
+        let specs = self.extract(&content)?;
+
+        let replace_futures = specs.into_iter().map(|spec| async {
+            let result = self.render_spec(&spec).await;
+            (spec.span, result)
         });
 
-        let mut replaces = futures::future::join_all(replace_futures)
-            .await
-            .into_iter()
-            .collect::<Result<Vec<_>>>()?;
+        let results = futures::future::join_all(replace_futures).await;
+        let replaces = collect_replaces(&content, results)?;
 
-        replaces.sort_by_key(|r| r.range.start);
+        apply_replaces(&mut content, replaces);
+        Ok(content)
+    }
 
-        for replace in replaces.into_iter().rev() {
-            let trimmed_range = trim_replace_range(&content, &replace.range);
-            content.replace_range(trimmed_range, &replace.content)
+    /// Render a single diagram found by [extract][Self::extract] and return the html that
+    /// replaces it.
+    pub async fn render_spec(&self, spec: &DiagramSpec) -> Result<String> {
+        let response = self.fetch_render(spec, self.document_path.as_deref()).await?;
+        let html = process_xml(response, spec, &self.post_processors)?;
+        self.observe(self.document_path.as_deref(), spec, &html);
+        Ok(html)
+    }
+
+    /// Tell the render observer about a diagram about to be inlined.
+    fn observe(&self, document: Option<&Path>, spec: &DiagramSpec, html: &str) {
+        if let Some(observer) = &self.render_observer {
+            observer(document, spec, html);
         }
+    }
 
-        Ok(content)
+    /// Render a diagram source on its own, outside of any markdown, and return its html.
+    ///
+    /// The cache and post-processors apply as they do to diagrams in a document.
+    pub async fn render_source(&self, diagram_type: &str, source: String) -> Result<String> {
+        let spec = DiagramSpec::new("", 0..0, DiagramKind::Fence, diagram_type.to_string(), source);
+        self.render_spec(&spec).await
+    }
+
+    /// Get kroki's response for a diagram, from the cache or the network, and report how it went
+    /// to the fetch observer.
+    async fn fetch_render(&self, spec: &DiagramSpec, document: Option<&Path>) -> Result<String> {
+        let start = Instant::now();
+        let result = self.fetch_response(spec).await;
+        self.report_fetch(document, spec, start.elapsed(), &result);
+        result.map(|(response, _)| response)
+    }
+
+    /// Kroki's response and the endpoint that produced it, or `None` for a cache hit.
+    async fn fetch_response(&self, spec: &DiagramSpec) -> Result<(String, Option<&str>)> {
+        let key = RenderCache::key(spec);
+        if let Some(cached) = self.cached_render(spec, &key)? {
+            return Ok((cached, None));
+        }
+
+        let body = serde_json::to_string(&RenderRequest::from(spec)).expect("could no serialize kroki request");
+        let (result, endpoint) = self
+            .send_with_fallbacks(spec, |endpoint| {
+                let request = self.client.post(endpoint).body(body.clone());
+                async move {
+                    let response = request.send().await?;
+                    if let Err(error) = response.error_for_status_ref() {
+                        return Err(RequestError { error, body: response.text().await.ok() });
+                    }
+                    Ok(response.text().await?)
+                }
+            })
+            .await?;
+        self.store_render(&key, &result)?;
+        Ok((result, Some(endpoint)))
+    }
+
+    /// Send a diagram to its endpoints in turn until one renders it, moving on only after errors
+    /// another deployment may not have. Returns kroki's response and the endpoint that gave it.
+    ///
+    /// `send` makes one request; the async and blocking clients both go through here.
+    async fn send_with_fallbacks<'s, F, R>(&'s self, spec: &DiagramSpec, mut send: F) -> Result<(String, &'s str)>
+    where
+        F: FnMut(&'s str) -> R,
+        R: Future<Output = Result<String, RequestError>>,
+    {
+        let endpoints = self.endpoints.candidates(&spec.diagram_type);
+        let mut endpoints = endpoints.into_iter().peekable();
+        loop {
+            let endpoint = endpoints.next().expect("there is always a primary endpoint");
+            match send(endpoint).await {
+                Ok(text) => {
+                    log_endpoint(spec, endpoint);
+                    return Ok((text, endpoint));
+                }
+                Err(e) if endpoints.peek().is_some() && should_fall_back(&e.error) => {
+                    log_fallback(spec, endpoint, &e.error)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Tell the fetch observer how getting kroki's response for a diagram went.
+    fn report_fetch(
+        &self,
+        document: Option<&Path>,
+        spec: &DiagramSpec,
+        elapsed: Duration,
+        result: &Result<(String, Option<&str>)>,
+    ) {
+        if let Some(observer) = &self.fetch_observer {
+            observer(&FetchRecord {
+                document,
+                spec,
+                endpoint: result.as_ref().ok().and_then(|(_, endpoint)| *endpoint),
+                cached: matches!(result, Ok((_, None))),
+                elapsed,
+                error: result.as_ref().err(),
+            });
+        }
+    }
+
+    /// Render the diagrams of many documents, sending each distinct diagram to kroki only once.
+    ///
+    /// All documents are extracted first; diagrams with the same type and source share one render,
+    /// which is then inlined into every document that contains them. Each document gets its own
+    /// result, so one failing document doesn't stop the others.
+    pub async fn render_many<D: DocumentId>(&self, documents: Vec<(D, String)>) -> Vec<(D, Result<String>)> {
+        let extracted = documents
+            .into_iter()
+            .map(|(id, content)| {
+                let specs = self.for_document(id.document_path().map(Path::to_path_buf)).extract(&content);
+                (id, content, specs)
+            })
+            .collect::<Vec<_>>();
+
+        let mut unique = HashMap::new();
+        for (id, _, specs) in &extracted {
+            for spec in specs.iter().flatten() {
+                unique.entry(RenderCache::key(spec)).or_insert((spec, id.document_path()));
+            }
+        }
+        let fetches = unique
+            .into_iter()
+            .map(|(key, (spec, document))| async move { (key, self.fetch_render(spec, document).await) });
+        let responses: HashMap<_, _> = futures::future::join_all(fetches).await.into_iter().collect();
+
+        extracted
+            .into_iter()
+            .map(|(id, mut content, specs)| {
+                let result = specs.and_then(|specs| {
+                    let results = specs.into_iter().map(|spec| {
+                        let result = match &responses[&RenderCache::key(&spec)] {
+                            Ok(response) => process_xml(response.clone(), &spec, &self.post_processors)
+                                .inspect(|html| self.observe(id.document_path(), &spec, html)),
+                            Err(e) => Err(copy_error(e)),
+                        };
+                        (spec.span, result)
+                    });
+                    let replaces = collect_replaces(&content, results)?;
+                    apply_replaces(&mut content, replaces);
+                    Ok(content)
+                });
+                (id, result)
+            })
+            .collect()
     }
 
     /// Synchronously render and inline diagrams into the provided markdown string.
@@ -52,57 +197,220 @@ impl MdKroki {
     /// Should only be called from a sync context. In an async context, the normal [render][MdKroki::render] method
     /// is recommended.
     pub fn render_sync(&self, mut content: String) -> Result<String> {
-        let renders = self.get_render_requests(&content)?;
-
-        let mut replaces = renders
-            .map(|req| {
-                let result = self.blocking_client
-                    .post(&self.endpoint)
-                    .body(serde_json::to_string(&req).expect("could no serialize kroki request"))
-                    .send()
-                    .expect("could not send kroki request")
-                    .error_for_status()?
-                    .text()?;
-                let result = process_xml(result)?;
-                Ok::<ReplaceRequest, anyhow::Error>(ReplaceRequest {
-                    range: req.replace_range,
-                    content: result,
-                })
+        let specs = self.extract(&content)?;
+
+        let results = specs.into_iter().map(|spec| {
+            let start = Instant::now();
+            let response = self.fetch_response_sync(&spec);
+            self.report_fetch(self.document_path.as_deref(), &spec, start.elapsed(), &response);
+            let result = response.and_then(|(response, _)| {
+                process_xml(response, &spec, &self.post_processors)
+                    .inspect(|html| self.observe(self.document_path.as_deref(), &spec, html))
+            });
+            (spec.span, result)
+        });
+        let replaces = collect_replaces(&content, results)?;
+
+        apply_replaces(&mut content, replaces);
+        Ok(content)
+    }
+
+    /// Blocking version of [fetch_response][Self::fetch_response].
+    fn fetch_response_sync(&self, spec: &DiagramSpec) -> Result<(String, Option<&str>)> {
+        let key = RenderCache::key(spec);
+        if let Some(cached) = self.cached_render(spec, &key)? {
+            return Ok((cached, None));
+        }
+        let client = self.blocking_client.get()?;
+        let body = serde_json::to_string(&RenderRequest::from(spec)).expect("could no serialize kroki request");
+        let (result, endpoint) = self
+            .send_with_fallbacks(spec, |endpoint| {
+                std::future::ready((|| {
+                    let response = client.post(endpoint).body(body.clone()).send()?;
+                    if let Err(error) = response.error_for_status_ref() {
+                        return Err(RequestError { error, body: response.text().ok() });
+                    }
+                    Ok(response.text()?)
+                })())
             })
-            .collect::<Result<Vec<_>>>()?;
-        replaces.sort_by_key(|r| r.range.start);
+            .now_or_never()
+            .expect("blocking requests are done before their future is polled")?;
+        self.store_render(&key, &result)?;
+        Ok((result, Some(endpoint)))
+    }
 
-        for replace in replaces.into_iter().rev() {
-            let trimmed_range = trim_replace_range(&content, &replace.range);
-            content.replace_range(trimmed_range, &replace.content)
+    /// Look up a render in the cache. In offline mode a miss is an error.
+    fn cached_render(&self, spec: &DiagramSpec, key: &str) -> Result<Option<String>> {
+        if let Some(cached) = self.cache.as_ref().map(|cache| cache.get(key)).transpose()?.flatten() {
+            return Ok(Some(cached));
         }
+        if self.offline {
+            return Err(CacheMiss {
+                diagram_type: spec.diagram_type.clone(),
+                key: key.to_string(),
+            }
+            .into());
+        }
+        Ok(None)
+    }
 
-        Ok(content)
+    /// Read the contents of a referenced file with the configured path resolver.
+    ///
+    /// A `#name` suffix on the path selects one named diagram or region from the file.
+    fn resolve_path(&self, reference: PathBuf, root: Option<&str>) -> Result<ResolvedReference> {
+        let reference = reference.to_string_lossy();
+        let (path, fragment) = split_fragment(&reference);
+        let path = PathBuf::from(path.as_ref());
+        let (path, source) = match self.resolve_localized(&path, root) {
+            // `notes#draft` may be a file with a `#` in its name rather than a fragment.
+            Err(e) if fragment.is_some() && is_not_found(&e) => {
+                let whole = PathBuf::from(decode_hash(&reference).as_ref());
+                let (path, source) = self.resolve_localized(&whole, root).map_err(|whole_error| {
+                    if is_not_found(&whole_error) {
+                        e
+                    } else {
+                        whole_error
+                    }
+                })?;
+                return Ok(ResolvedReference { path, fragment: None, source });
+            }
+            resolved => resolved?,
+        };
+        let source = match fragment {
+            Some(fragment) => extract_fragment(&source, fragment)
+                .with_context(|| format!("in {}", path.display()))?,
+            None => source,
+        };
+        Ok(ResolvedReference {
+            path,
+            fragment: fragment.map(str::to_string),
+            source,
+        })
     }
 
-    fn get_render_requests<'a>(
-        &self,
-        content: &str,
-    ) -> Result<impl Iterator<Item = RenderRequest> + 'a> {
+    /// Replace `{lang}` in the path with the language, falling back to the default language if
+    /// the file doesn't exist. Returns where the file was found and its contents.
+    fn resolve_localized(&self, path: &Path, root: Option<&str>) -> Result<(PathBuf, String)> {
+        let template = path.to_string_lossy();
+        if !template.contains(LANG_PLACEHOLDER) {
+            return self.resolve_file(path.to_path_buf(), root);
+        }
+        let languages = self.localization.languages();
+        let Some((last, preferred)) = languages.split_last() else {
+            bail!("{template} contains {LANG_PLACEHOLDER}, but no language is set");
+        };
+        for language in preferred {
+            let localized = PathBuf::from(template.replace(LANG_PLACEHOLDER, language));
+            match self.resolve_file(localized.clone(), root) {
+                Err(e) if is_not_found(&e) => {
+                    log::debug!("{} doesn't exist, falling back to {last}", localized.display());
+                }
+                result => return result,
+            }
+        }
+        self.resolve_file(template.replace(LANG_PLACEHOLDER, last).into(), root)
+    }
+
+    fn resolve_file(&self, path: PathBuf, root: Option<&str>) -> Result<(PathBuf, String)> {
+        let source = match self.path_resolver.as_ref() {
+            PathResolver::None => bail!("path resolver required for content with file references"),
+            PathResolver::Path(res) => {
+                if root.is_some() {
+                    bail!("path resolver must accept a root argument for content that uses it");
+                }
+                res(path.clone())
+            }
+            PathResolver::PathAndRoot(res) => res(path.clone(), root),
+            PathResolver::Document(res) => res(path.clone(), root, self.document_path.as_deref()),
+            PathResolver::DocumentFile(res) => return res(path, root, self.document_path.as_deref()),
+        }?;
+        Ok((path, source))
+    }
+
+    /// Merge the file named by a `data` attribute into a Vega or Vega-Lite spec.
+    fn bind_data(&self, mut spec: DiagramSpec) -> Result<DiagramSpec> {
+        let Some(data) = spec.attributes.get("data") else {
+            return Ok(spec);
+        };
+        let path = PathBuf::from(data);
+        let content = self.resolve_path(path.clone(), spec.root.as_deref())?.source;
+        let mut rows = parse_rows(&path, &content).with_context(|| format!("in {}", path.display()))?;
+        if rows.len() > self.data_row_limit {
+            log::warn!(
+                "line {}: {} has {} rows, only the first {} are used",
+                spec.lines.start(),
+                path.display(),
+                rows.len(),
+                self.data_row_limit
+            );
+            rows.truncate(self.data_row_limit);
+        }
+        let dataset = spec.attributes.get("data-name").map(String::as_str);
+        spec.source = inject_rows(&spec.diagram_type, &spec.source, rows, dataset)?;
+        Ok(spec)
+    }
+
+    /// Run the source generator for a `generate` attribute.
+    fn generate_source(&self, name: &str) -> Result<String> {
+        match &self.source_generator {
+            Some(generator) => generator(name),
+            None => bail!("source generator required for content with `generate` attributes"),
+        }
+    }
+
+    /// The diagram type implied by a file's extension, as for file references without a type.
+    pub fn infer_type(&self, path: &Path) -> Result<String> {
+        let reference = path.to_string_lossy();
+        let (path, _) = split_fragment(&reference);
+        let extension = Path::new(path.as_ref())
+            .extension()
+            .map(|extension| normalize_extension(&extension.to_string_lossy()))
+            .ok_or_else(|| anyhow!("missing type tag, and {path} has no extension to infer it from"))?;
+        self.extensions
+            .get(&extension)
+            .cloned()
+            .ok_or_else(|| anyhow!("missing type tag, and no diagram type is known for .{extension} files"))
+    }
+
+    fn store_render(&self, key: &str, result: &str) -> Result<()> {
+        match &self.cache {
+            Some(cache) => cache.put(key, result),
+            None => Ok(()),
+        }
+    }
+
+    /// Find every diagram in the markdown string without rendering it.
+    ///
+    /// File references are read through the path resolver, so each spec carries the diagram
+    /// source. Errors carry a [SourceLine] context pointing at the offending element; the first
+    /// malformed diagram fails the whole document. See [extract_each][Self::extract_each] to
+    /// get the others anyway.
+    pub fn extract(&self, content: &str) -> Result<Vec<DiagramSpec>> {
+        self.extract_each(content).into_iter().collect()
+    }
+
+    /// Like [extract][Self::extract], but a malformed diagram doesn't stop the search: every
+    /// diagram gets its own result, in document order.
+    pub fn extract_each(&self, content: &str) -> Vec<Result<DiagramSpec>> {
         #[derive(PartialEq, Eq)]
         enum ParserState {
             InImage {
-                diagram_type: String,
-                diagram_source: String,
-                replace_start: usize,
+                spec: DiagramSpec,
+                collapsed: bool,
             },
             InKrokiReferenceTag {
-                diagram_type: String,
-                diagram_source: String,
-                replace_start: usize,
+                spec: DiagramSpec,
             },
             InKrokiInlineTag {
                 diagram_type: String,
+                attributes: BTreeMap<String, String>,
                 content_start: usize,
                 replace_start: usize,
             },
             InCode {
                 diagram_type: String,
+                attributes: BTreeMap<String, String>,
+                diagram_source: String,
             },
             InPre(usize),
             Out,
@@ -110,9 +418,11 @@ impl MdKroki {
 
         let mut state = ParserState::Out;
 
-        let mut requests = Vec::new();
+        let mut specs = Vec::new();
 
-        Parser::new_ext(content, Options::all()).into_offset_iter().try_for_each(|(e, offset)| {
+        for (e, offset) in Parser::new_ext(content, Options::all()).into_offset_iter() {
+            let element_start = offset.start;
+            let result = (|| -> Result<()> {
                 match e {
                     Event::Html(ref tag) if tag.as_ref() == "<pre>" => {
                         state = match state {
@@ -135,116 +445,260 @@ impl MdKroki {
                             (tag.to_string(), true)
                         };
                         let element = Element::parse(xml.as_bytes())?;
-                        let diagram_type = element.attributes.get("type").ok_or_else(|| anyhow!("missing type tag"))?.clone();
-                        if !element.attributes.contains_key("path") {
+                        let attributes: BTreeMap<_, _> = element.attributes.into_iter().collect();
+                        if let Some(generator) = attributes.get("generate").cloned() {
+                            if attributes.contains_key("path") {
+                                bail!("kroki tag can't have both a `path` and a `generate` attribute");
+                            }
+                            let diagram_type = attributes.get("type").ok_or_else(|| anyhow!("missing type tag"))?.clone();
+                            let source = self.generate_source(&generator)?;
+                            let spec = DiagramSpec {
+                                root: attributes.get("root").cloned(),
+                                attributes,
+                                generator: Some(generator),
+                                ..DiagramSpec::new(content, offset, DiagramKind::Tag, diagram_type, source)
+                            };
+                            if closed {
+                                specs.push(Ok(spec))
+                            } else {
+                                state = ParserState::InKrokiReferenceTag { spec }
+                            }
+                            return Ok(());
+                        }
+                        if !attributes.contains_key("path") {
+                            let diagram_type = attributes.get("type").ok_or_else(|| anyhow!("missing type tag"))?.clone();
                             if closed {
                                 bail!("kroki tag must either have an inlined diagram or a `path` attribute.");
                             }
-                            state = ParserState::InKrokiInlineTag { diagram_type, content_start: offset.end, replace_start: offset.start };
+                            state = ParserState::InKrokiInlineTag { diagram_type, attributes, content_start: offset.end, replace_start: offset.start };
                             return Ok(());
                         }
-                        let path: PathBuf = element.attributes.get("path")
+                        let path: PathBuf = attributes.get("path")
                             .ok_or_else(|| anyhow!("src tag required"))?.parse()?;
-                        let path_root = element.attributes.get("root").map(|s| s.as_str());
-                        let diagram_source = match &self.path_resolver {
-                            PathResolver::None => bail!("path resolver required for content with file references"),
-                            PathResolver::Path(res) => {
-                                if path_root.is_some() {
-                                    bail!("path resolver must accept a root argument for content that uses it");
-                                }
-                                res(path)?
-                            }
-                            PathResolver::PathAndRoot(res) => res(path, path_root)?
+                        // Without a type attribute, the file extension decides.
+                        let diagram_type = match attributes.get("type") {
+                            Some(diagram_type) => diagram_type.clone(),
+                            None => self.infer_type(&path)?,
+                        };
+                        let root = attributes.get("root").cloned();
+                        let resolved = self.resolve_path(path.clone(), root.as_deref())?;
+                        let spec = DiagramSpec {
+                            attributes,
+                            reference: Some(path),
+                            path: Some(resolved.path),
+                            fragment: resolved.fragment,
+                            root,
+                            ..DiagramSpec::new(content, offset, DiagramKind::Tag, diagram_type, resolved.source)
                         };
                         if closed {
-                            requests.push(RenderRequest {
-                                diagram_source,
-                                diagram_type,
-                                output_format: "svg".to_string(),
-                                replace_range: offset
-                            })
+                            specs.push(Ok(spec))
                         } else {
-                            state = ParserState::InKrokiReferenceTag { diagram_type, diagram_source, replace_start: offset.start }
+                            state = ParserState::InKrokiReferenceTag { spec }
                         }
                     }
                     Event::Html(ref tag) if tag.contains("</kroki>") => {
-                        if let ParserState::InKrokiInlineTag { ref diagram_type, content_start, replace_start } = state {
-                            let diagram_source = content[content_start..offset.start].to_string();
-                            requests.push(RenderRequest {
-                                diagram_source,
-                                diagram_type: diagram_type.clone(),
-                                output_format: "svg".to_string(),
-                                replace_range: replace_start .. offset.end
-                            });
-                            state = ParserState::Out;
-                        } else if let ParserState::InKrokiReferenceTag { ref diagram_type, ref diagram_source, replace_start } = state {
-                            requests.push(RenderRequest {
-                                diagram_source: diagram_source.clone(),
-                                diagram_type: diagram_type.clone(),
-                                output_format: "svg".to_string(),
-                                replace_range: replace_start .. offset.end
-                            });
-                            state = ParserState::Out;
+                        match std::mem::replace(&mut state, ParserState::Out) {
+                            ParserState::InKrokiInlineTag { diagram_type, attributes, content_start, replace_start } => {
+                                let source = self.localization.substitute_labels(content[content_start..offset.start].to_string())?;
+                                specs.push(Ok(DiagramSpec {
+                                    root: attributes.get("root").cloned(),
+                                    attributes,
+                                    ..DiagramSpec::new(content, replace_start..offset.end, DiagramKind::Tag, diagram_type, source)
+                                }));
+                            }
+                            ParserState::InKrokiReferenceTag { spec } => {
+                                let span = spec.span.start..offset.end;
+                                specs.push(Ok(DiagramSpec {
+                                    lines: lines(content, &span),
+                                    span,
+                                    ..spec
+                                }));
+                            }
+                            other => state = other,
                         }
                     }
                     _ if matches!(state, ParserState::InKrokiReferenceTag {..} | ParserState::InKrokiInlineTag {..}) => {},
-                    Event::Start(Tag::Image(LinkType::Inline, ref url, _)) => {
-                        if let Ok((diagram_type, path)) = sscanf!(url, "kroki-{String}:{PathBuf}") {
-                            let diagram_source = match &self.path_resolver {
-                                PathResolver::None => bail!("path resolver required for content with file references"),
-                                PathResolver::Path(res) => res(path)?,
-                                PathResolver::PathAndRoot(res) => res(path, None)?
+                    // Any link type: pulldown-cmark resolves reference, collapsed and shortcut links to their definition.
+                    Event::Start(Tag::Image(link_type, ref url, ref title)) => {
+                        if let Some(ImageReference { diagram_type, root, path }) = parse_image_reference(url) {
+                            let diagram_type = match diagram_type {
+                                Some(diagram_type) => diagram_type,
+                                None => self.infer_type(&path)?,
+                            };
+                            let resolved = self.resolve_path(path.clone(), root.as_deref())?;
+                            let spec = DiagramSpec {
+                                reference: Some(path),
+                                path: Some(resolved.path),
+                                fragment: resolved.fragment,
+                                root,
+                                alt: Some(String::new()),
+                                title: Some(title.to_string()).filter(|t| !t.is_empty()),
+                                ..DiagramSpec::new(content, offset, DiagramKind::Image, diagram_type, resolved.source)
+                            };
+                            state = ParserState::InImage {
+                                spec,
+                                collapsed: link_type == LinkType::Collapsed,
                             };
-                            state = ParserState::InImage { diagram_type, diagram_source, replace_start: offset.start };
+                        }
+                    }
+                    Event::Text(ref text) | Event::Code(ref text) if matches!(state, ParserState::InImage { .. }) => {
+                        if let ParserState::InImage { ref mut spec, .. } = state {
+                            spec.alt.get_or_insert_with(String::new).push_str(text);
                         }
                     }
                     Event::End(Tag::Image(..)) => {
-                        if let ParserState::InImage { ref diagram_type, ref diagram_source, replace_start } = state {
-                            requests.push(RenderRequest {
-                                diagram_source: diagram_source.to_string(),
-                                diagram_type: diagram_type.clone(),
-                                output_format: "svg".to_string(),
-                                replace_range: replace_start .. offset.end
-                            });
-                            state = ParserState::Out;
+                        if let ParserState::InImage { spec, collapsed } = std::mem::replace(&mut state, ParserState::Out) {
+                            // The parser's range for a collapsed reference `![label][]` stops before the `[]`.
+                            let replace_end = if collapsed && content[offset.end..].starts_with("[]") {
+                                offset.end + 2
+                            } else {
+                                offset.end
+                            };
+                            let span = spec.span.start..replace_end;
+                            specs.push(Ok(DiagramSpec {
+                                lines: lines(content, &span),
+                                span,
+                                alt: spec.alt.filter(|a| !a.is_empty()),
+                                ..spec
+                            }));
                         }
                     }
-                    Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref lang))) => {
+                    Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info))) => {
+                        // Only the first word of the info string is the language; the rest are attributes.
+                        let lang = info.split_whitespace().next().unwrap_or_default();
                         if let Ok(diagram_type) = sscanf!(lang, "kroki-{String}") {
-                            state = ParserState::InCode { diagram_type }
+                            state = ParserState::InCode { diagram_type, attributes: info_attributes(info), diagram_source: String::new() }
+                        }
+                    }
+                    Event::Text(ref text) if matches!(state, ParserState::InCode { .. }) => {
+                        // The parser strips fences and container prefixes (list indentation, `>`) from the text.
+                        if let ParserState::InCode { ref mut diagram_source, .. } = state {
+                            diagram_source.push_str(text);
                         }
                     }
                     Event::End(Tag::CodeBlock(..)) => {
-                        if let ParserState::InCode { ref diagram_type } = state {
-                            let content_start = content[offset.clone()].trim_start().find(char::is_whitespace).ok_or_else(|| anyhow!("code block needs whitespace somewhere"))? + offset.start;
-                            let content_end = content[offset.clone()].trim_end().rfind(|c| c != '`').unwrap() + offset.start + 1;
-                            let diagram_source = content[content_start..content_end].to_string();
-                            requests.push(RenderRequest {
-                                diagram_source,
-                                diagram_type: diagram_type.clone(),
-                                output_format: "svg".to_string(),
-                                replace_range: offset
-                            });
-                            state = ParserState::Out;
+                        if let ParserState::InCode { diagram_type, attributes, diagram_source } = std::mem::replace(&mut state, ParserState::Out) {
+                            let diagram_source = self.localization.substitute_labels(diagram_source)?;
+                            specs.push(Ok(DiagramSpec {
+                                root: attributes.get("root").cloned(),
+                                attributes,
+                                ..DiagramSpec::new(content, offset, DiagramKind::Fence, diagram_type, diagram_source)
+                            }));
                         }
                     }
                     _ => {},
                 }
                 Ok(())
-            })?;
+            })();
+            // Report the malformed element and carry on with the next one.
+            if let Err(e) = result {
+                specs.push(Err(e.context(SourceLine(line_number(content, element_start)))));
+                state = ParserState::Out;
+            }
+        }
 
-        Ok(requests.into_iter())
+        specs
+            .into_iter()
+            .map(|spec| {
+                let spec = spec?;
+                let line = *spec.lines.start();
+                self.bind_data(spec).with_context(|| SourceLine(line))
+            })
+            .collect()
     }
 }
 
+/// The body of a kroki render request. Its JSON form is also the render cache key.
 #[derive(Serialize, Debug)]
-struct RenderRequest {
-    diagram_source: String,
-    diagram_type: String,
-    output_format: String,
+pub(crate) struct RenderRequest<'a> {
+    pub(crate) diagram_source: &'a str,
+    pub(crate) diagram_type: &'a str,
+    pub(crate) output_format: &'a str,
+}
+
+impl<'a> From<&'a DiagramSpec> for RenderRequest<'a> {
+    fn from(spec: &'a DiagramSpec) -> Self {
+        RenderRequest {
+            diagram_source: &spec.source,
+            diagram_type: &spec.diagram_type,
+            output_format: "svg",
+        }
+    }
+}
+
+/// A file reference read through the path resolver.
+struct ResolvedReference {
+    /// Where the file was found.
+    path: PathBuf,
+    /// The block or region selected from the file, if any.
+    fragment: Option<String>,
+    /// The file's contents, or just the selected fragment.
+    source: String,
+}
+
+/// The parts of a `kroki-<type>:[root]<path>` or `kroki:[root]<path>` image url.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ImageReference {
+    /// `None` in the `kroki:` form, where the file extension decides.
+    pub(crate) diagram_type: Option<String>,
+    pub(crate) root: Option<String>,
+    pub(crate) path: PathBuf,
+}
+
+/// Parse the url of a markdown image that references a diagram file.
+///
+/// The root can be given in two ways, `kroki-<type>:<root>:<path>` or `kroki-<type>://<root>/<path>`.
+/// Roots are at least two characters long, so Windows drive letters aren't mistaken for them.
+/// The type can be left out as in `kroki:<path>`. Returns `None` for urls that aren't kroki references.
+pub(crate) fn parse_image_reference(url: &str) -> Option<ImageReference> {
+    let (diagram_type, reference) = match url.strip_prefix("kroki:") {
+        Some(reference) => (None, reference),
+        None => {
+            let (diagram_type, reference) = url.strip_prefix("kroki-")?.split_once(':')?;
+            if diagram_type.is_empty() || reference.is_empty() {
+                return None;
+            }
+            (Some(diagram_type.to_string()), reference)
+        }
+    };
+    if reference.is_empty() {
+        return None;
+    }
+
+    let is_root = |root: &str| root.len() >= 2 && root.chars().all(|c| c.is_ascii_alphabetic());
+    let (root, path) = if let Some(rest) = reference.strip_prefix("//") {
+        let (root, path) = rest.split_once('/').unwrap_or((rest, ""));
+        (Some(root), path)
+    } else {
+        match reference.split_once(':') {
+            Some((root, path)) if is_root(root) => (Some(root), path),
+            _ => (None, reference),
+        }
+    };
+
+    Some(ImageReference {
+        root: root.map(str::to_string),
+        path: PathBuf::from(path),
+        diagram_type,
+    })
+}
+
+/// Error context recording the (1-based) markdown line a diagram starts on.
+///
+/// Errors from [extract][MdKroki::extract] and rendering carry it; get it back with
+/// `error.downcast_ref::<SourceLine>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLine(pub usize);
+
+impl std::fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "at line {}", self.0)
+    }
+}
 
-    #[serde(skip)]
-    replace_range: Range<usize>,
+/// 1-based line number of a byte offset.
+pub(crate) fn line_number(content: &str, offset: usize) -> usize {
+    content.as_bytes()[..offset].iter().filter(|&&b| b == b'\n').count() + 1
 }
 
 struct ReplaceRequest {
@@ -252,6 +706,99 @@ struct ReplaceRequest {
     content: String,
 }
 
+fn log_endpoint(spec: &DiagramSpec, endpoint: &str) {
+    log::debug!("rendered {} diagram with {endpoint}", spec.diagram_type);
+}
+
+fn log_fallback(spec: &DiagramSpec, endpoint: &str, error: &reqwest::Error) {
+    log::warn!(
+        "{endpoint} could not render {} diagram, trying the next endpoint: {error}",
+        spec.diagram_type
+    );
+}
+
+/// A copy of a shared render error for one of the documents that contain the diagram.
+///
+/// Cache misses keep their type so they are still reported together.
+fn copy_error(error: &anyhow::Error) -> anyhow::Error {
+    match error.downcast_ref::<CacheMiss>() {
+        Some(miss) => miss.clone().into(),
+        None => anyhow!("{error:#}"),
+    }
+}
+
+/// Collect successful renders, sorted by position.
+///
+/// The first failed render is returned with a [SourceLine] context. Cache misses are gathered
+/// into a single error, so an offline build reports every missing diagram at once instead of
+/// stopping at the first.
+fn collect_replaces(
+    content: &str,
+    results: impl IntoIterator<Item = (Range<usize>, Result<String>)>,
+) -> Result<Vec<ReplaceRequest>> {
+    let mut replaces = Vec::new();
+    let mut misses = Vec::new();
+    for (range, result) in results {
+        match result {
+            Ok(result) => replaces.push(ReplaceRequest {
+                range,
+                content: result,
+            }),
+            Err(e) => {
+                let line = line_number(content, range.start);
+                match e.downcast_ref::<CacheMiss>() {
+                    Some(miss) => misses.push(format!("line {line}: {miss}")),
+                    None => return Err(e.context(SourceLine(line))),
+                }
+            }
+        }
+    }
+    if !misses.is_empty() {
+        bail!(
+            "{} diagrams missing from the render cache in offline mode:\n  {}",
+            misses.len(),
+            misses.join("\n  ")
+        );
+    }
+
+    replaces.sort_by_key(|r| r.range.start);
+    Ok(replaces)
+}
+
+/// Splice rendered diagrams into the markdown, back to front so earlier ranges stay valid.
+fn apply_replaces(content: &mut String, replaces: Vec<ReplaceRequest>) {
+    for replace in replaces.into_iter().rev() {
+        let trimmed_range = trim_replace_range(content, &replace.range);
+        let replacement = indent_replacement(content, trimmed_range.start, &replace.content);
+        content.replace_range(trimmed_range, &replacement)
+    }
+}
+
+/// Re-indent a multi-line replacement to stay inside the container (list item, blockquote)
+/// of the element it replaces.
+///
+/// The first line keeps the position of the original element. Continuation lines get the
+/// same prefix, with list markers turned into spaces and `>` markers kept.
+pub(crate) fn indent_replacement<'a>(content: &str, start: usize, replacement: &'a str) -> Cow<'a, str> {
+    let line_start = content[..start].rfind('\n').map_or(0, |i| i + 1);
+    let prefix = &content[line_start..start];
+    let is_container_prefix = prefix
+        .chars()
+        .all(|c| matches!(c, ' ' | '\t' | '>' | '-' | '*' | '+' | '.' | ')' | '0'..='9'));
+    if prefix.is_empty() || !is_container_prefix || !replacement.contains('\n') {
+        return Cow::Borrowed(replacement);
+    }
+
+    let continuation: String = prefix
+        .chars()
+        .map(|c| match c {
+            '>' | '\t' => c,
+            _ => ' ',
+        })
+        .collect();
+    Cow::Owned(replacement.replace('\n', &format!("\n{continuation}")))
+}
+
 fn trim_replace_range(content: &str, range: &Range<usize>) -> Range<usize> {
     let s = &content[range.clone()];
     let trimmed_start = s.len() - s.trim_start().len();
@@ -259,9 +806,59 @@ fn trim_replace_range(content: &str, range: &Range<usize>) -> Range<usize> {
     (range.start + trimmed_start)..(range.end - trimmed_end)
 }
 
-fn process_xml(xml: String) -> Result<String> {
+/// Extract the svg from a kroki response, run the post-processors and wrap it in html.
+fn process_xml(xml: String, spec: &DiagramSpec, post_processors: &[PostProcessor]) -> Result<String> {
     let svg_start = xml.find("<svg").ok_or_else(|| anyhow!("Missing <svg>"))?;
     let svg_end = xml.rfind("</svg>").ok_or_else(|| anyhow!("Missing </svg>"))? + 6;
-    let svg_content = &xml[svg_start..svg_end];
-    Ok(format!("<pre class='diagram-kroki'>{}</pre>", svg_content.trim()))
+    let mut svg_content = xml[svg_start..svg_end].trim().to_string();
+
+    if let Some(title) = &spec.title {
+        let open_tag_end = svg_open_tag_end(&svg_content).ok_or_else(|| anyhow!("Unterminated <svg>"))?;
+        svg_content.insert_str(open_tag_end, &format!("<title>{}</title>", escape_html(title)));
+    }
+
+    let mut output = RenderedOutput {
+        svg: svg_content,
+        classes: vec!["diagram-kroki".to_string()],
+        attributes: match &spec.alt {
+            Some(alt) => vec![("role".to_string(), "img".to_string()), ("aria-label".to_string(), alt.clone())],
+            None => Vec::new(),
+        },
+    };
+    for post_process in post_processors {
+        output = post_process(spec, output).context("post-processing failed")?;
+    }
+
+    let mut attributes = String::new();
+    for (name, value) in &output.attributes {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':')) {
+            bail!("invalid attribute name {name:?}");
+        }
+        attributes.push_str(&format!(" {name}='{}'", escape_html(value)));
+    }
+    let classes = escape_html(&output.classes.join(" "));
+    Ok(format!("<pre class='{classes}'{attributes}>{}</pre>", output.svg))
+}
+
+/// Byte position just after the `>` that closes the opening `<svg ...>` tag.
+fn svg_open_tag_end(svg: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in svg.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (None, '>') => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Escape `text` for html, both as element content and inside a quoted attribute value.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
 }
@@ -0,0 +1,35 @@
+//! Choosing which kroki deployment renders a diagram.
+
+use std::collections::HashMap;
+
+/// The default endpoint, per-type overrides, and fallbacks tried when an endpoint is unavailable.
+#[derive(Debug, Clone)]
+pub(crate) struct Endpoints {
+    pub(crate) default: String,
+    pub(crate) routes: HashMap<String, String>,
+    pub(crate) fallbacks: Vec<String>,
+}
+
+impl Endpoints {
+    /// Endpoints to try for a diagram type, in order. The first one is never missing.
+    pub(crate) fn candidates(&self, diagram_type: &str) -> Vec<&str> {
+        let primary = self.routes.get(diagram_type).unwrap_or(&self.default);
+        let mut candidates = vec![primary.as_str()];
+        for fallback in &self.fallbacks {
+            if !candidates.contains(&fallback.as_str()) {
+                candidates.push(fallback);
+            }
+        }
+        candidates
+    }
+}
+
+/// Whether a failed request should be retried on the next endpoint.
+///
+/// Only connection problems and server errors qualify; a 4xx means the diagram itself is
+/// rejected and another deployment would reject it too.
+pub(crate) fn should_fall_back(error: &reqwest::Error) -> bool {
+    error.is_connect()
+        || error.is_timeout()
+        || error.status().is_some_and(|status| status.is_server_error())
+}
@@ -0,0 +1,110 @@
+//! Diagrams found in a markdown document, independent of rendering.
+
+use crate::md_kroki::render::line_number;
+use std::collections::BTreeMap;
+use std::ops::{Range, RangeInclusive};
+use std::path::PathBuf;
+
+/// The markdown syntax a diagram was written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiagramKind {
+    /// A `<kroki>` tag, with the diagram inlined, referenced by its `path` attribute or generated.
+    Tag,
+    /// A fenced code block with a `kroki-<type>` language.
+    Fence,
+    /// An image whose url is a `kroki-<type>:<path>` file reference.
+    Image,
+}
+
+/// A diagram found by [MdKroki::extract][super::MdKroki::extract].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiagramSpec {
+    /// Byte range of the markdown that the rendered diagram replaces.
+    pub span: Range<usize>,
+    /// 1-based lines of the markdown that the span covers.
+    pub lines: RangeInclusive<usize>,
+    /// Syntax the diagram was written in.
+    pub kind: DiagramKind,
+    /// Kroki diagram type, like `plantuml` or `mermaid`.
+    pub diagram_type: String,
+    /// Attributes of a `<kroki>` tag, or the `key=value` words after the language of a fence.
+    /// Bare words in a fence info string have an empty value.
+    pub attributes: BTreeMap<String, String>,
+    /// Referenced file as written in the markdown, with any `{lang}` and `#fragment`.
+    /// `None` for inlined diagrams.
+    pub reference: Option<PathBuf>,
+    /// The file the source was read from: `{lang}` replaced, `#fragment` removed, and, with a
+    /// [document_file_resolver][super::MdKrokiBuilder::document_file_resolver], the full path
+    /// the resolver read. Other resolvers leave it relative to [root][Self::root].
+    pub path: Option<PathBuf>,
+    /// Name of the PlantUML block or region selected from the file by a `#fragment`.
+    pub fragment: Option<String>,
+    /// Root that the path and any `data` file are relative to, if one was given.
+    pub root: Option<String>,
+    /// Name of the source generator, for a `<kroki>` tag with a `generate` attribute.
+    pub generator: Option<String>,
+    /// Diagram source, read through the path resolver for file references.
+    pub source: String,
+    /// Alt text of an image reference.
+    pub alt: Option<String>,
+    /// Title of an image reference.
+    pub title: Option<String>,
+}
+
+impl DiagramSpec {
+    /// A spec without attributes, path or image text.
+    pub(crate) fn new(
+        content: &str,
+        span: Range<usize>,
+        kind: DiagramKind,
+        diagram_type: String,
+        source: String,
+    ) -> Self {
+        DiagramSpec {
+            lines: lines(content, &span),
+            span,
+            kind,
+            diagram_type,
+            attributes: BTreeMap::new(),
+            reference: None,
+            path: None,
+            fragment: None,
+            root: None,
+            generator: None,
+            source,
+            alt: None,
+            title: None,
+        }
+    }
+}
+
+/// 1-based lines covered by a byte range of `content`.
+pub(crate) fn lines(content: &str, span: &Range<usize>) -> RangeInclusive<usize> {
+    let last = span.end.max(span.start + 1) - 1;
+    line_number(content, span.start)..=line_number(content, last.min(content.len()))
+}
+
+/// Parse the words after the language of a fence info string, like `title="Two words" dark`.
+pub(crate) fn info_attributes(info: &str) -> BTreeMap<String, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    for c in info.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => words.push(std::mem::take(&mut word)),
+            c => word.push(c),
+        }
+    }
+    words.push(word);
+
+    words
+        .into_iter()
+        .filter(|word| !word.is_empty())
+        .skip(1)
+        .map(|word| match word.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (word, String::new()),
+        })
+        .collect()
+}
@@ -0,0 +1,1106 @@
+use crate::md_kroki::render::{
+    indent_replacement, line_number, parse_image_reference, ImageReference, SourceLine,
+};
+use crate::md_kroki::{DiagramKind, DiagramSpec, MdKroki, RenderCache};
+use crate::temp_dir::TempDir;
+use pretty_assertions::assert_eq;
+use std::path::{Path, PathBuf};
+
+fn requests(renderer: &MdKroki, content: &str) -> Vec<(String, String, String)> {
+    renderer
+        .extract(content)
+        .unwrap()
+        .into_iter()
+        .map(|spec| (spec.diagram_type, spec.source, content[spec.span].to_string()))
+        .collect()
+}
+
+#[test]
+fn finds_inline_tag() {
+    let content = "# Title\n\n<kroki type=\"erd\">\n[Person]\n</kroki>\n\ntext\n";
+    let found = requests(&MdKroki::new(), content);
+
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].0, "erd");
+    assert_eq!(found[0].1.trim(), "[Person]");
+    assert!(found[0].2.starts_with("<kroki"));
+    assert!(found[0].2.trim_end().ends_with("</kroki>"));
+}
+
+#[test]
+fn finds_fenced_code_block() {
+    let content = "text\n\n```kroki-mermaid\ngraph TD\n  A --> B\n```\n";
+    let found = requests(&MdKroki::new(), content);
+
+    assert_eq!(
+        found,
+        vec![(
+            "mermaid".to_string(),
+            "graph TD\n  A --> B\n".to_string(),
+            "```kroki-mermaid\ngraph TD\n  A --> B\n```".to_string()
+        )]
+    );
+}
+
+#[test]
+fn ignores_other_code_blocks() {
+    let content = "```rust\nfn main() {}\n```\n\n```\nplain\n```\n";
+    assert!(requests(&MdKroki::new(), content).is_empty());
+}
+
+#[test]
+fn resolves_image_references() {
+    let renderer = MdKroki::builder()
+        .path_resolver(|path| Ok(format!("contents of {}", path.display())))
+        .build();
+    let found = requests(&renderer, "![diagram](kroki-excalidraw:my/file.excalidraw)\n");
+
+    assert_eq!(
+        found,
+        vec![(
+            "excalidraw".to_string(),
+            "contents of my/file.excalidraw".to_string(),
+            "![diagram](kroki-excalidraw:my/file.excalidraw)".to_string()
+        )]
+    );
+}
+
+#[test]
+fn errors_carry_source_line() {
+    let content = "# Title\n\ntext\n\n<kroki />\n";
+    let error = MdKroki::new()
+        .extract(content)
+        .expect_err("missing type should fail");
+
+    assert_eq!(error.downcast_ref::<SourceLine>(), Some(&SourceLine(5)));
+    assert_eq!(error.root_cause().to_string(), "missing type tag");
+}
+
+#[test]
+fn extract_each_carries_on_after_malformed_diagrams() {
+    let content = "<kroki />\n\n```kroki-dot\ndigraph {}\n```\n\n<kroki type=\"dot\" />\n\n```kroki-mermaid\ngraph TD\n```\n";
+    let results = MdKroki::new().extract_each(content);
+
+    let summary: Vec<_> = results
+        .iter()
+        .map(|result| match result {
+            Ok(spec) => Ok((*spec.lines.start(), spec.diagram_type.clone())),
+            Err(e) => Err(e.downcast_ref::<SourceLine>().map(|line| line.0)),
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            Err(Some(1)),
+            Ok((3, "dot".to_string())),
+            Err(Some(7)),
+            Ok((9, "mermaid".to_string())),
+        ]
+    );
+    // extract still fails on the first one.
+    let error = MdKroki::new().extract(content).unwrap_err();
+    assert_eq!(error.downcast_ref::<SourceLine>(), Some(&SourceLine(1)));
+}
+
+#[test]
+fn counts_lines_from_one() {
+    let content = "a\nb\nc";
+    assert_eq!(line_number(content, 0), 1);
+    assert_eq!(line_number(content, 2), 2);
+    assert_eq!(line_number(content, content.len()), 3);
+}
+
+#[test]
+fn offline_mode_serves_cache_and_lists_misses() {
+    let dir = TempDir::new("cache");
+    let cache = RenderCache::new(dir.path());
+    let renderer = MdKroki::builder()
+        .cache(cache.clone())
+        .offline(true)
+        .build();
+
+    let content = "```kroki-mermaid\ngraph TD\n```\n\n```kroki-dot\ndigraph {}\n```\n".to_string();
+    let error = renderer.render_sync(content.clone()).unwrap_err().to_string();
+    assert!(error.starts_with("2 diagrams missing from the render cache"));
+    assert!(error.contains("line 1: mermaid diagram"));
+    assert!(error.contains("line 5: dot diagram"));
+
+    for spec in renderer.extract(&content).unwrap() {
+        let svg = format!("<svg>{}</svg>", spec.diagram_type);
+        cache.put(&RenderCache::key(&spec), &svg).unwrap();
+    }
+    let rendered = renderer.render_sync(content).unwrap();
+    assert_eq!(
+        rendered,
+        "<pre class='diagram-kroki'><svg>mermaid</svg></pre>\n\n<pre class='diagram-kroki'><svg>dot</svg></pre>\n"
+    );
+}
+
+#[test]
+fn concurrent_cache_writes_of_one_key_never_mix() {
+    let dir = TempDir::new("cache-race");
+    let cache = RenderCache::new(dir.path().join("cache"));
+    let key = "a".repeat(64);
+    let contents: Vec<String> = (0..8).map(|n| n.to_string().repeat(10_000)).collect();
+
+    std::thread::scope(|scope| {
+        for content in &contents {
+            let (cache, key) = (&cache, &key);
+            scope.spawn(move || {
+                for _ in 0..20 {
+                    cache.put(key, content).unwrap();
+                }
+            });
+        }
+    });
+
+    let stored = cache.get(&key).unwrap().unwrap();
+    assert!(contents.contains(&stored), "entry mixes several writes");
+    let names: Vec<_> = std::fs::read_dir(cache.dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(names, [key.as_str()], "temporary files were left behind");
+}
+
+#[test]
+fn cache_archives_round_trip_and_reject_foreign_paths() {
+    let dir = TempDir::new("cache-archive");
+    let source = RenderCache::new(dir.path().join("source"));
+    let (first, second) = ("1".repeat(64), "2".repeat(64));
+    source.put(&first, "<svg>1</svg>").unwrap();
+    source.put(&second, "<svg>2</svg>").unwrap();
+    dir.write("source/notes.txt", "not an entry");
+
+    let archive = dir.path().join("renders.tar");
+    assert_eq!(source.export(&archive).unwrap(), 2);
+    let target = RenderCache::new(dir.path().join("target"));
+    assert_eq!(target.import(&archive).unwrap(), 2);
+    assert_eq!(target.get(&first).unwrap().as_deref(), Some("<svg>1</svg>"));
+    assert_eq!(target.get(&second).unwrap().as_deref(), Some("<svg>2</svg>"));
+
+    // tar::Builder refuses to write such paths, so build the headers by hand.
+    let escape = dir.path().join("escape");
+    let outside = dir.path().join("outside");
+    for name in ["../outside".to_string(), outside.display().to_string()] {
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(std::fs::File::create(dir.path().join("evil.tar")).unwrap());
+        builder.append(&header, &b"evil"[..]).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let error = RenderCache::new(&escape)
+            .import(&dir.path().join("evil.tar"))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("unexpected entry in cache archive: {name}")
+        );
+        assert!(!outside.exists());
+    }
+}
+
+#[test]
+fn invalid_connection_settings_fail_to_build() {
+    let error = MdKroki::builder()
+        .header("bad header", "value")
+        .try_build()
+        .err()
+        .expect("header names can't contain spaces");
+    assert_eq!(error.root_cause().to_string(), "invalid HTTP header name");
+
+    assert!(MdKroki::builder()
+        .ca_certificate("not a certificate")
+        .try_build()
+        .is_err());
+
+    assert!(MdKroki::builder()
+        .header("X-Team", "docs")
+        .bearer_token("secret")
+        .proxy("http://localhost:3128")
+        .try_build()
+        .is_ok());
+}
+
+#[test]
+fn document_resolver_receives_document_path() {
+    let renderer = MdKroki::builder()
+        .document_path_resolver(|path, root: Option<&str>, document: Option<&std::path::Path>| {
+            Ok(format!(
+                "{} {root:?} {:?}",
+                path.display(),
+                document.map(|d| d.display().to_string())
+            ))
+        })
+        .build();
+    let content = "<kroki type=\"dot\" path=\"a.dot\" root=\"book\" />\n";
+
+    let shared = requests(&renderer, content);
+    assert_eq!(shared[0].1, "a.dot Some(\"book\") None");
+
+    let chapter = renderer.for_document(Some("guide/intro.md".into()));
+    let found = requests(&chapter, content);
+    assert_eq!(found[0].1, "a.dot Some(\"book\") Some(\"guide/intro.md\")");
+}
+
+#[test]
+fn finds_fences_in_containers() {
+    let content = "\
+~~~kroki-plantuml
+A -> B
+~~~
+
+- item
+
+  ```kroki-graphviz title=\"deps\"
+  digraph {
+    a -> b
+  }
+  ```
+
+> quote
+>
+> ````kroki-mermaid
+> graph TD
+> ````
+";
+    let found = requests(&MdKroki::new(), content)
+        .into_iter()
+        .map(|(diagram_type, source, _)| (diagram_type, source))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        found,
+        vec![
+            ("plantuml".to_string(), "A -> B\n".to_string()),
+            ("graphviz".to_string(), "digraph {\n  a -> b\n}\n".to_string()),
+            ("mermaid".to_string(), "graph TD\n".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn replacements_are_indented_to_their_container() {
+    let svg = "<pre>\n<svg/>\n</pre>";
+
+    let list = "- item\n\n  ```kroki-dot";
+    assert_eq!(
+        indent_replacement(list, list.find('`').unwrap(), svg),
+        "<pre>\n  <svg/>\n  </pre>"
+    );
+
+    let quote = "> - ```kroki-dot";
+    assert_eq!(
+        indent_replacement(quote, quote.find('`').unwrap(), svg),
+        "<pre>\n>   <svg/>\n>   </pre>"
+    );
+
+    let top_level = "text\n```kroki-dot";
+    assert_eq!(indent_replacement(top_level, 5, svg), svg);
+
+    let paragraph = "see ![x](kroki-dot:a.dot)";
+    assert_eq!(indent_replacement(paragraph, 4, svg), svg);
+}
+
+#[test]
+fn resolves_reference_style_images() {
+    let renderer = MdKroki::builder()
+        .path_resolver(|path| Ok(path.display().to_string()))
+        .build();
+    let content = "\
+Full ![Architecture][arch-diagram], collapsed ![arch-diagram][] and shortcut ![arch-diagram].
+
+[arch-diagram]: kroki-plantuml:arch.puml \"System overview\"
+";
+    let found = renderer
+        .extract(content)
+        .unwrap()
+        .into_iter()
+        .map(|spec| (content[spec.span].to_string(), spec.source, spec.alt, spec.title))
+        .collect::<Vec<_>>();
+
+    let title = Some("System overview".to_string());
+    assert_eq!(
+        found,
+        vec![
+            (
+                "![Architecture][arch-diagram]".to_string(),
+                "arch.puml".to_string(),
+                Some("Architecture".to_string()),
+                title.clone()
+            ),
+            (
+                "![arch-diagram][]".to_string(),
+                "arch.puml".to_string(),
+                Some("arch-diagram".to_string()),
+                title.clone()
+            ),
+            (
+                "![arch-diagram]".to_string(),
+                "arch.puml".to_string(),
+                Some("arch-diagram".to_string()),
+                title
+            ),
+        ]
+    );
+}
+
+#[test]
+fn image_alt_and_title_become_accessible_text() {
+    let dir = TempDir::new("alt");
+    let cache = RenderCache::new(dir.path());
+    let renderer = MdKroki::builder()
+        .path_resolver(|_| Ok("digraph {}".to_string()))
+        .cache(cache.clone())
+        .offline(true)
+        .build();
+    let content = "![A & B > C](kroki-dot:deps.dot 'Dependency graph')\n".to_string();
+    for spec in renderer.extract(&content).unwrap() {
+        let svg = "<?xml version=\"1.0\"?><svg width=\"1\" data-x='a>b'><g/></svg>";
+        cache.put(&RenderCache::key(&spec), svg).unwrap();
+    }
+
+    assert_eq!(
+        renderer.render_sync(content).unwrap(),
+        "<pre class='diagram-kroki' role='img' aria-label='A &amp; B &gt; C'>\
+         <svg width=\"1\" data-x='a>b'><title>Dependency graph</title><g/></svg></pre>\n"
+    );
+}
+
+#[test]
+fn parses_roots_in_image_references() {
+    let reference = |diagram_type: &str, root: Option<&str>, path: &str| {
+        Some(ImageReference {
+            diagram_type: Some(diagram_type.to_string()),
+            root: root.map(str::to_string),
+            path: path.into(),
+        })
+    };
+
+    assert_eq!(
+        parse_image_reference("kroki-plantuml:diagrams/x.puml"),
+        reference("plantuml", None, "diagrams/x.puml")
+    );
+    assert_eq!(
+        parse_image_reference("kroki-plantuml:book:/diagrams/x.puml"),
+        reference("plantuml", Some("book"), "/diagrams/x.puml")
+    );
+    assert_eq!(
+        parse_image_reference("kroki-plantuml://source/shared/x.puml"),
+        reference("plantuml", Some("source"), "shared/x.puml")
+    );
+    assert_eq!(
+        parse_image_reference("kroki-dot:C:/diagrams/x.dot"),
+        reference("dot", None, "C:/diagrams/x.dot")
+    );
+    assert_eq!(parse_image_reference("images/x.png"), None);
+}
+
+#[test]
+fn image_roots_are_passed_to_the_resolver() {
+    let renderer = MdKroki::builder()
+        .path_and_root_resolver(|path, root: Option<&str>| Ok(format!("{root:?} {}", path.display())))
+        .build();
+    let found = requests(&renderer, "![x](kroki-dot://book/shared/x.dot)\n");
+    assert_eq!(found[0].1, "Some(\"book\") shared/x.dot");
+
+    let without_root_support = MdKroki::builder()
+        .path_resolver(|path| Ok(path.display().to_string()))
+        .build();
+    assert!(without_root_support
+        .extract("![x](kroki-dot:book:x.dot)\n")
+        .is_err());
+}
+
+#[test]
+fn selects_fragments_of_referenced_files() {
+    let source = "\
+@startuml overview
+A -> B
+@enduml
+
+@startuml(id=checkout)
+Cart -> Payment
+@enduml
+
+%% region: login
+graph TD
+  %% region: inner
+  A --> B
+  %% endregion
+%% endregion
+";
+    let renderer = MdKroki::builder()
+        .path_resolver(move |path| {
+            assert_eq!(path.to_str(), Some("flows.puml"));
+            Ok(source.to_string())
+        })
+        .build();
+    let content = "\
+<kroki type=\"plantuml\" path=\"flows.puml#checkout\" />
+
+![login](kroki-mermaid:flows.puml#login)
+";
+    let found = requests(&renderer, content)
+        .into_iter()
+        .map(|(_, source, _)| source)
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        found,
+        vec![
+            "@startuml(id=checkout)\nCart -> Payment\n@enduml\n".to_string(),
+            "graph TD\n  A --> B\n".to_string(),
+        ]
+    );
+
+    let error = renderer
+        .extract("<kroki type=\"plantuml\" path=\"flows.puml#refund\" />\n")
+        .expect_err("unknown fragments should fail");
+    assert_eq!(
+        error.root_cause().to_string(),
+        "no @start block or region named \"refund\" found"
+    );
+}
+
+#[test]
+fn hashes_in_file_names_are_not_fragments() {
+    let renderer = MdKroki::builder()
+        .path_resolver(|path| match path.to_str() {
+            Some("C#-classes.puml") => Ok("classes".to_string()),
+            Some("C#.puml") => Ok("@startuml overview\nA -> B\n@enduml\n".to_string()),
+            Some("notes#draft") => Ok("draft".to_string()),
+            _ => Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+        })
+        .build();
+    let content = "\
+![classes](kroki-plantuml:C#-classes.puml)
+
+<kroki type=\"plantuml\" path=\"C%23.puml#overview\" />
+
+<kroki type=\"plantuml\" path=\"notes#draft\" />
+";
+    let found = requests(&renderer, content)
+        .into_iter()
+        .map(|(diagram_type, source, _)| (diagram_type, source))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        found,
+        [
+            ("plantuml".to_string(), "classes".to_string()),
+            ("plantuml".to_string(), "@startuml overview\nA -> B\n@enduml\n".to_string()),
+            ("plantuml".to_string(), "draft".to_string()),
+        ]
+    );
+    assert_eq!(
+        renderer.infer_type(Path::new("C#-classes.puml")).unwrap(),
+        "plantuml"
+    );
+}
+
+/// Serve a single HTTP response on a local port and return the url.
+fn serve_once(status: &'static str, body: &'static str) -> String {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(length) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                content_length = length.trim().parse().unwrap();
+            }
+            if line == "\r\n" {
+                break;
+            }
+        }
+        reader.read_exact(&mut vec![0; content_length]).unwrap();
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        reader.get_mut().write_all(response.as_bytes()).unwrap();
+    });
+    url
+}
+
+#[test]
+fn routes_by_type_and_falls_back_on_server_errors() {
+    let unreachable = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/", listener.local_addr().unwrap())
+    };
+    let renderer = MdKroki::builder()
+        .endpoint(&unreachable)
+        .route("dot", serve_once("503 Service Unavailable", "busy"))
+        .fallback_endpoint(serve_once("200 OK", "<svg>dot</svg>"))
+        .build();
+    let rendered = renderer.render_sync("```kroki-dot\ndigraph {}\n```\n".to_string());
+    assert_eq!(
+        rendered.unwrap(),
+        "<pre class='diagram-kroki'><svg>dot</svg></pre>\n"
+    );
+
+    let renderer = MdKroki::builder()
+        .endpoint(serve_once("400 Bad Request", "syntax error"))
+        .fallback_endpoint(&unreachable)
+        .build();
+    let error = renderer
+        .render_sync("```kroki-dot\ndigraph {\n```\n".to_string())
+        .unwrap_err();
+    assert_eq!(error.downcast_ref::<SourceLine>(), Some(&SourceLine(1)));
+    let message = format!("{error:#}");
+    assert!(message.contains("400 Bad Request"), "{message}");
+    // Kroki's explanation of the rejection is kept.
+    assert!(message.ends_with(": syntax error"), "{message}");
+}
+
+#[test]
+fn extracts_diagram_specs() {
+    let renderer = MdKroki::builder()
+        .path_and_root_resolver(|path, _root: Option<&str>| Ok(format!("{}\n", path.display())))
+        .build();
+    let content = "\
+# Title
+
+<kroki type=\"erd\" theme=\"dark\">
+[Person]
+</kroki>
+
+```kroki-dot title=\"Two words\" wide
+digraph {}
+```
+
+![Flow](kroki-mermaid://book/flow.mmd)
+";
+    let specs = renderer.extract(content).unwrap();
+
+    let attributes = |pairs: &[(&str, &str)]| {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+    assert_eq!(
+        specs,
+        vec![
+            DiagramSpec {
+                span: 9..59,
+                lines: 3..=5,
+                kind: DiagramKind::Tag,
+                diagram_type: "erd".to_string(),
+                attributes: attributes(&[("theme", "dark"), ("type", "erd")]),
+                reference: None,
+                path: None,
+                fragment: None,
+                root: None,
+                generator: None,
+                source: "[Person]\n".to_string(),
+                alt: None,
+                title: None,
+            },
+            DiagramSpec {
+                span: 60..110,
+                lines: 7..=9,
+                kind: DiagramKind::Fence,
+                diagram_type: "dot".to_string(),
+                attributes: attributes(&[("title", "Two words"), ("wide", "")]),
+                reference: None,
+                path: None,
+                fragment: None,
+                root: None,
+                generator: None,
+                source: "digraph {}\n".to_string(),
+                alt: None,
+                title: None,
+            },
+            DiagramSpec {
+                span: 112..150,
+                lines: 11..=11,
+                kind: DiagramKind::Image,
+                diagram_type: "mermaid".to_string(),
+                attributes: Default::default(),
+                reference: Some("flow.mmd".into()),
+                path: Some("flow.mmd".into()),
+                fragment: None,
+                root: Some("book".to_string()),
+                generator: None,
+                source: "flow.mmd\n".to_string(),
+                alt: Some("Flow".to_string()),
+                title: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn specs_carry_the_resolved_path_of_references() {
+    let renderer = MdKroki::builder()
+        .language("de")
+        .default_language("en")
+        .document_file_resolver(|path, root, document| {
+            let base = match root {
+                Some("book") => PathBuf::from("/book"),
+                _ => Path::new("/book/src").join(document.unwrap().parent().unwrap()),
+            };
+            let file = base.join(&path);
+            match path.to_str() {
+                Some("en/flows.puml") => Ok((file, "@startuml checkout\nA -> B\n@enduml\n".to_string())),
+                Some("shared/flow.mmd") => Ok((file, "graph TD".to_string())),
+                _ => Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+            }
+        })
+        .build()
+        .for_document(Some(PathBuf::from("guide/chapter.md")));
+    let content = "\
+<kroki type=\"plantuml\" path=\"{lang}/flows.puml#checkout\" />
+
+![Flow](kroki-mermaid:book:shared/flow.mmd)
+";
+    let specs = renderer.extract(content).unwrap();
+
+    let paths: Vec<_> = specs
+        .iter()
+        .map(|spec| (spec.reference.clone(), spec.path.clone(), spec.fragment.clone()))
+        .collect();
+    assert_eq!(
+        paths,
+        [
+            (
+                Some(PathBuf::from("{lang}/flows.puml#checkout")),
+                Some(PathBuf::from("/book/src/guide/en/flows.puml")),
+                Some("checkout".to_string()),
+            ),
+            (
+                Some(PathBuf::from("shared/flow.mmd")),
+                Some(PathBuf::from("/book/shared/flow.mmd")),
+                None,
+            ),
+        ]
+    );
+}
+
+#[test]
+fn post_processors_adjust_rendered_diagrams() {
+    use crate::md_kroki::postprocess::{add_classes, add_data_attributes, replace_colors};
+
+    let dir = TempDir::new("post");
+    let cache = RenderCache::new(dir.path());
+    let colors = [("#FEFECE".to_string(), "#E8F0FE".to_string())].into();
+    let renderer = MdKroki::builder()
+        .cache(cache.clone())
+        .offline(true)
+        .post_process(replace_colors(colors))
+        .post_process(add_classes(vec!["brand".to_string()]))
+        .post_process(add_data_attributes(
+            [("diagram".to_string(), "{type}@{line}".to_string())].into(),
+        ))
+        .post_process(|spec, output| {
+            assert_eq!(spec.diagram_type, "dot");
+            Ok(output)
+        })
+        .build();
+    let content = "text\n\n```kroki-dot\ndigraph {}\n```\n".to_string();
+    for spec in renderer.extract(&content).unwrap() {
+        let svg = "<svg><rect fill=\"#fefece\"/><rect fill=\"#FEFECE80\"/></svg>";
+        cache.put(&RenderCache::key(&spec), svg).unwrap();
+    }
+
+    assert_eq!(
+        renderer.render_sync(content).unwrap(),
+        "text\n\n<pre class='diagram-kroki brand' data-diagram='dot@3'>\
+         <svg><rect fill=\"#E8F0FE\"/><rect fill=\"#FEFECE80\"/></svg></pre>\n"
+    );
+}
+
+#[test]
+fn optimizer_shrinks_svg_markup() {
+    use crate::md_kroki::optimize::optimize_svg;
+
+    let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\"  width=\"120.456789px\">\
+        <!--SRC=[abc]--><?plantuml 1.2023?>
+        <metadata><rdf:RDF/></metadata>
+        <g id=\"elem1.25\" transform=\"translate(0,0)\">
+            <path d=\"M10.123456,20.5 L1.5.25 3.14159e2,.333333\" opacity=\"1\"/>
+            <rect x=\"0\" y=\"0.001\" width=\"5\"/>
+            <text x=\"0.0004\" y=\"-12.999\">a  b</text>
+        </g></svg>";
+
+    assert_eq!(
+        optimize_svg(svg, 2),
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"120.46px\">\
+         <g id=\"elem1.25\">\
+         <path d=\"M10.12,20.5 L1.5 0.25 3.14159e2,0.33\"/>\
+         <rect width=\"5\"/>\
+         <text x=\"0\" y=\"-13\">a  b</text></g></svg>"
+    );
+}
+
+#[test]
+fn optimizer_rounds_only_geometric_attributes() {
+    use crate::md_kroki::optimize::optimize_svg;
+
+    let svg = "<svg viewBox=\"0 0 10.123 20.456\" data-version=\"1.2023.10\">\
+        <rect width=\"3.14159\" stroke-width=\"1.005\" stroke-dasharray=\"2.125,1.125\" \
+        data-ratio=\"0.333333\" fill-opacity=\"0.125\"/>\
+        <circle cx=\"1.111\" cy=\"2.222\" r=\"3.333\" class=\"n1.234\"/></svg>";
+
+    assert_eq!(
+        optimize_svg(svg, 1),
+        "<svg viewBox=\"0 0 10.1 20.5\" data-version=\"1.2023.10\">\
+         <rect width=\"3.1\" stroke-width=\"1\" stroke-dasharray=\"2.125,1.125\" \
+         data-ratio=\"0.333333\" fill-opacity=\"0.125\"/>\
+         <circle cx=\"1.1\" cy=\"2.2\" r=\"3.3\" class=\"n1.234\"/></svg>"
+    );
+}
+
+#[test]
+fn optimizer_keeps_zero_positions_where_they_are_not_the_default() {
+    use crate::md_kroki::postprocess::{optimize, RenderedOutput};
+
+    // A tspan with x="0" starts a new line at the left edge; filter and mask regions default to -10%.
+    let svg = "<svg><text x=\"10\"><tspan x=\"0\" dy=\"1.2em\">second line</tspan></text>\
+        <filter id=\"f\" x=\"0\" y=\"0\" width=\"1\" height=\"1\"><feFlood opacity=\"1\"/></filter>\
+        <mask id=\"m\" x=\"0\" y=\"0\"/><pattern id=\"p\" x=\"0\" y=\"0\"/><clipPath id=\"c\" x=\"0\"/>\
+        <use x=\"0\" y=\"0\" href=\"#m\"/></svg>";
+    let spec = MdKroki::new().extract("```kroki-plantuml\nA -> B\n```\n").unwrap().remove(0);
+    let output = optimize(2)(&spec, RenderedOutput { svg: svg.to_string(), classes: vec![], attributes: vec![] }).unwrap();
+
+    assert_eq!(
+        output.svg,
+        "<svg><text x=\"10\"><tspan x=\"0\" dy=\"1.2em\">second line</tspan></text>\
+         <filter id=\"f\" x=\"0\" y=\"0\" width=\"1\" height=\"1\"><feFlood opacity=\"1\"/></filter>\
+         <mask id=\"m\" x=\"0\" y=\"0\"/><pattern id=\"p\" x=\"0\" y=\"0\"/><clipPath id=\"c\" x=\"0\"/>\
+         <use href=\"#m\"/></svg>"
+    );
+}
+
+#[test]
+fn optimizer_keeps_whitespace_in_text() {
+    use crate::md_kroki::optimize::optimize_svg;
+
+    let svg = "<svg>\n  <text x=\"1\"><tspan>a</tspan> <tspan>b</tspan></text>\n  \
+        <g xml:space=\"preserve\"> <desc>  </desc> <g xml:space=\"default\"> </g></g>\n</svg>";
+
+    assert_eq!(
+        optimize_svg(svg, 2),
+        "<svg><text x=\"1\"><tspan>a</tspan> <tspan>b</tspan></text>\
+         <g xml:space=\"preserve\"> <desc>  </desc> <g xml:space=\"default\"></g></g></svg>"
+    );
+}
+
+#[test]
+fn render_many_sends_shared_diagrams_once() {
+    // The server answers a single request, so a second render of the same diagram would fail.
+    let renderer = MdKroki::builder()
+        .endpoint(serve_once("200 OK", "<svg>shared</svg>"))
+        .build();
+    let shared = "```kroki-dot\ndigraph {}\n```\n";
+    let documents = vec![
+        ("a.md".to_string(), format!("# A\n\n{shared}")),
+        ("b.md".to_string(), format!("{shared}\n{shared}")),
+        ("c.md".to_string(), "<kroki type=\"dot\" />\n".to_string()),
+    ];
+
+    let results = tokio_test::block_on(renderer.render_many(documents));
+
+    let html = "<pre class='diagram-kroki'><svg>shared</svg></pre>\n";
+    assert_eq!(results[0].0, "a.md");
+    assert_eq!(results[0].1.as_ref().unwrap(), &format!("# A\n\n{html}"));
+    assert_eq!(results[1].1.as_ref().unwrap(), &format!("{html}\n{html}"));
+    assert!(results[2].1.is_err(), "a tag without source fails only its own document");
+}
+
+#[test]
+fn infers_diagram_type_from_extension() {
+    let renderer = MdKroki::builder()
+        .path_and_root_resolver(|path, _root: Option<&str>| Ok(path.display().to_string()))
+        .extension(".WSD", "plantuml")
+        .build();
+    let content = "\
+<kroki path=\"auth.puml\" />
+
+<kroki path=\"flows.txt\" type=\"mermaid\"></kroki>
+
+![deps](kroki:book:deps.gv)
+
+![seq](kroki://source/seq.wsd)
+";
+    let found = renderer
+        .extract(content)
+        .unwrap()
+        .into_iter()
+        .map(|spec| spec.diagram_type)
+        .collect::<Vec<_>>();
+    assert_eq!(found, ["plantuml", "mermaid", "graphviz", "plantuml"]);
+
+    assert_eq!(
+        parse_image_reference("kroki:a/b.puml"),
+        Some(ImageReference {
+            diagram_type: None,
+            root: None,
+            path: "a/b.puml".into(),
+        })
+    );
+
+    let error = renderer
+        .extract("<kroki path=\"notes.txt\" />\n")
+        .expect_err("unknown extension");
+    assert_eq!(
+        error.root_cause().to_string(),
+        "missing type tag, and no diagram type is known for .txt files"
+    );
+}
+
+#[test]
+fn localizes_referenced_files_and_labels() {
+    let renderer = MdKroki::builder()
+        .path_resolver(|path| match path.to_str() {
+            Some("flows/zh/login.puml") => Ok("中文".to_string()),
+            Some("flows/en/login.puml" | "flows/en/logout.puml") => Ok("english".to_string()),
+            _ => Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+        })
+        .language("zh")
+        .default_language("en")
+        .labels("zh", [("user".to_string(), "用户".to_string())].into())
+        .labels("en", [("user".to_string(), "User".to_string()), ("shop".to_string(), "Shop".to_string())].into())
+        .build();
+    let content = "\
+<kroki type=\"plantuml\" path=\"flows/{lang}/login.puml\" />
+
+![logout](kroki-plantuml:flows/{lang}/logout.puml)
+
+```kroki-plantuml
+{{label:user}} -> {{label:shop}}
+```
+";
+    let found = requests(&renderer, content)
+        .into_iter()
+        .map(|(_, source, _)| source)
+        .collect::<Vec<_>>();
+    assert_eq!(found, ["中文", "english", "用户 -> Shop\n"]);
+
+    let error = renderer
+        .extract("<kroki type=\"erd\">\n{{label:missing}}\n</kroki>\n")
+        .expect_err("unknown label");
+    assert_eq!(error.root_cause().to_string(), "no label \"missing\" for language \"zh\"");
+
+    // Without labels, placeholders are left alone.
+    let found = requests(&MdKroki::new(), "```kroki-dot\n{{label:user}}\n```\n");
+    assert_eq!(found[0].1, "{{label:user}}\n");
+}
+
+#[test]
+fn generated_sources_come_from_the_named_generator() {
+    let renderer = MdKroki::builder()
+        .source_generator(|name| match name {
+            "deps" => Ok("digraph { a -> b }".to_string()),
+            _ => anyhow::bail!("unknown generator {name:?}"),
+        })
+        .build();
+    let specs = renderer
+        .extract("<kroki type=\"dot\" generate=\"deps\" />\n\n<kroki type=\"dot\" generate=\"deps\"></kroki>\n")
+        .unwrap();
+    assert_eq!(specs.len(), 2);
+    assert_eq!(specs[0].generator.as_deref(), Some("deps"));
+    assert_eq!(specs[0].source, "digraph { a -> b }");
+    assert_eq!(specs[1].span, 38..80);
+
+    let error = renderer
+        .extract("<kroki type=\"dot\" generate=\"rm\" />\n")
+        .expect_err("unknown generator");
+    assert_eq!(error.root_cause().to_string(), "unknown generator \"rm\"");
+
+    let error = MdKroki::new()
+        .extract("<kroki type=\"dot\" generate=\"deps\" />\n")
+        .expect_err("no generator");
+    assert_eq!(
+        error.root_cause().to_string(),
+        "source generator required for content with `generate` attributes"
+    );
+}
+
+#[test]
+fn merges_data_files_into_vega_specs() {
+    let renderer = MdKroki::builder()
+        .path_resolver(|path| {
+            Ok(match path.to_str() {
+                Some("metrics.csv") => "day,latency,ok,host\n1,2.5,true,a\n2,,false,7\n3,4,TRUE,b\n",
+                Some("points.json") => r#"[{"x": 1}]"#,
+                Some("chart.json") => r#"{"mark": "line", "data": {"url": "old.csv", "name": "metrics", "format": {"parse": {"day": "date"}}}}"#,
+                Some("vega.json") => r#"{"data": [{"name": "table", "url": "old.csv"}, {"name": "other"}]}"#,
+                _ => anyhow::bail!("unexpected path {}", path.display()),
+            }
+            .to_string())
+        })
+        .data_row_limit(2)
+        .build();
+    let sources = |content: &str| -> Vec<serde_json::Value> {
+        renderer
+            .extract(content)
+            .unwrap()
+            .into_iter()
+            .map(|spec| serde_json::from_str(&spec.source).unwrap())
+            .collect()
+    };
+
+    let found = sources("<kroki type=\"vegalite\" path=\"chart.json\" data=\"metrics.csv\" />\n");
+    assert_eq!(
+        found[0],
+        serde_json::json!({
+            "mark": "line",
+            "data": {
+                "name": "metrics",
+                "format": {"parse": {"day": "date"}},
+                "values": [
+                    {"day": 1, "latency": 2.5, "ok": true, "host": "a"},
+                    {"day": 2, "latency": null, "ok": false, "host": "7"},
+                ],
+            },
+        })
+    );
+
+    let found = sources("```kroki-vega data=points.json\n{\"marks\": []}\n```\n\n<kroki type=\"vega\" path=\"vega.json\" data=\"points.json\" data-name=\"other\" />\n");
+    assert_eq!(
+        found,
+        [
+            serde_json::json!({"marks": [], "data": [{"name": "table", "values": [{"x": 1}]}]}),
+            serde_json::json!({"data": [
+                {"name": "table", "url": "old.csv"},
+                {"name": "other", "values": [{"x": 1}]},
+            ]}),
+        ]
+    );
+
+    let error = renderer
+        .extract("\n```kroki-mermaid data=metrics.csv\ngraph TD\n```\n")
+        .expect_err("data on a non-vega diagram");
+    assert_eq!(error.to_string(), "at line 2");
+    assert_eq!(
+        error.root_cause().to_string(),
+        "`data` is only supported for vega and vegalite diagrams, not mermaid"
+    );
+}
+
+#[test]
+fn inline_data_files_honor_the_root_attribute() {
+    let renderer = MdKroki::builder()
+        .path_and_root_resolver(|path, root: Option<&str>| match (root, path.to_str()) {
+            (Some("book"), Some("metrics.csv")) => Ok("day\n1\n".to_string()),
+            _ => anyhow::bail!("{root:?} {} should be read from the book root", path.display()),
+        })
+        .build();
+    let content = "\
+<kroki type=\"vegalite\" root=\"book\" data=\"metrics.csv\">
+{\"mark\": \"bar\"}
+</kroki>
+
+```kroki-vegalite root=book data=metrics.csv
+{\"mark\": \"line\"}
+```
+";
+    let specs = renderer.extract(content).unwrap();
+
+    let expected = |mark: &str| serde_json::json!({"mark": mark, "data": {"values": [{"day": 1}]}});
+    let sources: Vec<serde_json::Value> = specs.iter().map(|spec| serde_json::from_str(&spec.source).unwrap()).collect();
+    assert_eq!(sources, [expected("bar"), expected("line")]);
+    assert!(specs.iter().all(|spec| spec.root.as_deref() == Some("book")));
+}
+
+#[test]
+fn csv_numbers_only_cover_plain_decimals() {
+    use crate::md_kroki::data::parse_rows;
+
+    let csv = "id,zip,code,amount,signed\n007,01234,1e3,0.5,+1\n8,99501,2E3,-12.25,2\n10,,3,0,3\n";
+    let rows = parse_rows(Path::new("table.csv"), csv).unwrap();
+    assert_eq!(
+        rows,
+        [
+            serde_json::json!({"id": "007", "zip": "01234", "code": "1e3", "amount": 0.5, "signed": "+1"}),
+            serde_json::json!({"id": "8", "zip": "99501", "code": "2E3", "amount": -12.25, "signed": "2"}),
+            serde_json::json!({"id": "10", "zip": "", "code": "3", "amount": 0, "signed": "3"}),
+        ]
+    );
+}
+
+#[test]
+fn render_observer_sees_every_inlined_diagram() {
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let renderer = MdKroki::builder()
+        .endpoint(serve_once("200 OK", "<svg>shared</svg>"))
+        .on_render({
+            let seen = seen.clone();
+            move |document: Option<&Path>, spec: &DiagramSpec, html: &str| {
+                let document = document.map(Path::to_path_buf);
+                seen.lock().unwrap().push((document, *spec.lines.start(), html.to_string()));
+            }
+        })
+        .build();
+    let shared = "```kroki-dot\ndigraph {}\n```\n";
+    let documents = vec![
+        (PathBuf::from("a.md"), shared.to_string()),
+        (PathBuf::from("b.md"), format!("# B\n\n{shared}")),
+    ];
+    tokio_test::block_on(renderer.render_many(documents));
+
+    let mut seen = seen.lock().unwrap().clone();
+    seen.sort();
+    let html = "<pre class='diagram-kroki'><svg>shared</svg></pre>".to_string();
+    assert_eq!(
+        seen,
+        [
+            (Some(PathBuf::from("a.md")), 1, html.clone()),
+            (Some(PathBuf::from("b.md")), 3, html),
+        ]
+    );
+}
+
+#[test]
+fn fetch_observer_reports_cache_hits_endpoints_and_failures() {
+    use crate::md_kroki::FetchRecord;
+    use std::sync::{Arc, Mutex};
+
+    let dir = TempDir::new("fetch");
+    let cache = RenderCache::new(dir.path());
+    let endpoint = serve_once("200 OK", "<svg>dot</svg>");
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let renderer = MdKroki::builder()
+        .endpoint(&endpoint)
+        .route("ditaa", serve_once("400 Bad Request", "syntax error"))
+        .cache(cache.clone())
+        .on_fetch({
+            let seen = seen.clone();
+            move |fetch: &FetchRecord| {
+                let endpoint = fetch.endpoint.map(str::to_string);
+                let failed = fetch.error.is_some();
+                seen.lock()
+                    .unwrap()
+                    .push((*fetch.spec.lines.start(), endpoint, fetch.cached, failed));
+            }
+        })
+        .build();
+    let content = "```kroki-mermaid\ngraph TD\n```\n\n```kroki-dot\ndigraph {}\n```\n\n```kroki-ditaa\n+--+\n```\n";
+    let mermaid = renderer.extract(content).unwrap().remove(0);
+    cache.put(&RenderCache::key(&mermaid), "<svg>mermaid</svg>").unwrap();
+
+    renderer.render_sync(content.to_string()).unwrap_err();
+    assert_eq!(
+        seen.lock().unwrap().clone(),
+        [
+            (1, None, true, false),
+            (5, Some(endpoint), false, false),
+            (9, None, false, true),
+        ]
+    );
+}
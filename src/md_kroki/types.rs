@@ -0,0 +1,53 @@
+//! Diagram types implied by the extension of a referenced file.
+
+use std::collections::HashMap;
+
+/// Extensions recognized without configuration, with the kroki diagram type they map to.
+pub(crate) const DEFAULT_EXTENSIONS: &[(&str, &str)] = &[
+    ("puml", "plantuml"),
+    ("plantuml", "plantuml"),
+    ("pu", "plantuml"),
+    ("iuml", "plantuml"),
+    ("c4puml", "c4plantuml"),
+    ("dot", "graphviz"),
+    ("gv", "graphviz"),
+    ("mmd", "mermaid"),
+    ("mermaid", "mermaid"),
+    ("bpmn", "bpmn"),
+    ("excalidraw", "excalidraw"),
+    ("d2", "d2"),
+    ("dbml", "dbml"),
+    ("ditaa", "ditaa"),
+    ("erd", "erd"),
+    ("nomnoml", "nomnoml"),
+    ("pikchr", "pikchr"),
+    ("bob", "svgbob"),
+    ("svgbob", "svgbob"),
+    ("vega", "vega"),
+    ("vl", "vegalite"),
+    ("vegalite", "vegalite"),
+    ("wavedrom", "wavedrom"),
+    ("bytefield", "bytefield"),
+    ("blockdiag", "blockdiag"),
+    ("seqdiag", "seqdiag"),
+    ("actdiag", "actdiag"),
+    ("nwdiag", "nwdiag"),
+    ("packetdiag", "packetdiag"),
+    ("rackdiag", "rackdiag"),
+    ("dsl", "structurizr"),
+    ("tikz", "tikz"),
+    ("uxf", "umlet"),
+];
+
+/// Normalize an extension for lookups: no leading dot, lowercase.
+pub(crate) fn normalize_extension(extension: &str) -> String {
+    extension.trim_start_matches('.').to_ascii_lowercase()
+}
+
+/// The built-in extension table.
+pub(crate) fn default_extensions() -> HashMap<String, String> {
+    DEFAULT_EXTENSIONS
+        .iter()
+        .map(|(extension, diagram_type)| (extension.to_string(), diagram_type.to_string()))
+        .collect()
+}
@@ -0,0 +1,60 @@
+//! Limits which files diagram references may read.
+//!
+//! Referenced files are sent to the kroki server, which may be a public one, so a chapter must
+//! not be able to pull in arbitrary files from the machine building the book.
+
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Directories that referenced files must stay inside.
+#[derive(Debug, Clone)]
+pub struct PathPolicy {
+    /// Canonical book root, followed by the canonical extra directories.
+    allowed: Vec<PathBuf>,
+    allow_system_root: bool,
+}
+
+impl PathPolicy {
+    /// Allow files inside the book root and `extra_dirs`, which are relative to the book root.
+    /// Fails if any of the directories doesn't exist.
+    pub fn new(book_root: &Path, extra_dirs: &[PathBuf], allow_system_root: bool) -> Result<Self> {
+        let canonical = |dir: &Path| {
+            dir.canonicalize()
+                .with_context(|| format!("could not resolve allowed directory {}", dir.display()))
+        };
+        let book_root = canonical(book_root)?;
+        let mut allowed = vec![book_root.clone()];
+        for dir in extra_dirs {
+            allowed.push(canonical(&book_root.join(dir))?);
+        }
+        Ok(PathPolicy {
+            allowed,
+            allow_system_root,
+        })
+    }
+
+    /// Fail unless `root="system"` references were enabled.
+    pub fn check_system_root(&self) -> Result<()> {
+        if !self.allow_system_root {
+            bail!(r#"root="system" is disabled; set `allow-system-root = true` in book.toml to use it"#);
+        }
+        Ok(())
+    }
+
+    /// Read `path` if it resolves to a file inside one of the allowed directories.
+    ///
+    /// The path is canonicalized first, so neither `..` components nor symlinks can escape.
+    pub fn read(&self, path: &Path) -> Result<String> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("could not read {}", path.display()))?;
+        if !self.allowed.iter().any(|dir| canonical.starts_with(dir)) {
+            bail!(
+                "{} is outside the book root; add its directory to `allowed-dirs` in book.toml to use it",
+                path.display()
+            );
+        }
+        std::fs::read_to_string(&canonical)
+            .with_context(|| format!("could not read {}", path.display()))
+    }
+}
@@ -0,0 +1,182 @@
+//! The `preview` subcommand: serves a page with one rendered diagram file, which reloads
+//! whenever the file changes.
+
+use crate::config::KrokiConfig;
+use crate::KrokiPreprocessor;
+use anyhow::{anyhow, Context, Result};
+use boilerplate::md_kroki::{escape_html, MdKroki};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use mdbook::preprocess::Preprocessor;
+use mdbook::MDBook;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use tiny_http::{Header, Response, Server};
+
+/// How often the page asks whether the diagram changed, in milliseconds.
+const POLL_INTERVAL: u32 = 500;
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{name} - kroki preview</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+h1 small { color: #888; font-weight: normal; }
+.kroki-error { color: #b00; background: #fff5f5; border: 1px solid #b00; padding: 1em; white-space: pre-wrap; }
+</style>
+</head>
+<body>
+<h1>{name} <small>{type}</small></h1>
+{content}
+<script>
+const version = "{version}";
+setInterval(async () => {
+  try {
+    const response = await fetch("/version");
+    if ((await response.text()) !== version) location.reload();
+  } catch (e) {}
+}, {interval});
+</script>
+</body>
+</html>
+"#;
+
+/// Arguments of the `preview` subcommand.
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("preview")
+        .about("Serve a live preview of one diagram file")
+        .arg(
+            Arg::with_name("file")
+                .required(true)
+                .help("Diagram source file"),
+        )
+        .arg(
+            Arg::with_name("type")
+                .long("type")
+                .takes_value(true)
+                .help("Diagram type; inferred from the file extension by default"),
+        )
+        .arg(
+            Arg::with_name("book-dir")
+                .long("book-dir")
+                .takes_value(true)
+                .default_value(".")
+                .help("Root directory of the book whose kroki settings are used"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .takes_value(true)
+                .default_value("3001")
+                .help("Local port to serve the preview on"),
+        )
+}
+
+/// Runs the `preview` subcommand until it is interrupted.
+pub fn handle(args: &ArgMatches) -> Result<()> {
+    let book = MDBook::load(args.value_of("book-dir").expect("has default"))?;
+    let config = KrokiConfig::load(&book.config, KrokiPreprocessor.name())?;
+    let renderer = config.renderer(book.root.clone(), book.config.book.src.clone())?;
+
+    let file = PathBuf::from(args.value_of("file").expect("required"));
+    let diagram_type = match args.value_of("type") {
+        Some(diagram_type) => diagram_type.to_string(),
+        None => renderer
+            .infer_type(&file)
+            .context("pass --type to set the diagram type")?,
+    };
+    let port: u16 = args
+        .value_of("port")
+        .expect("has default")
+        .parse()
+        .context("invalid port")?;
+
+    let server = Server::http(("127.0.0.1", port))
+        .map_err(|e| anyhow!("could not listen on port {port}: {e}"))?;
+    log::info!("previewing {} at http://127.0.0.1:{port}/", file.display());
+
+    let mut preview = Preview {
+        file,
+        diagram_type,
+        renderer,
+        runtime: tokio::runtime::Builder::new_multi_thread().enable_all().build()?,
+        rendered: None,
+    };
+    for request in server.incoming_requests() {
+        let (body, content_type) = match request.url() {
+            "/" => (preview.page(), "text/html; charset=utf-8"),
+            "/version" => (preview.version(), "text/plain"),
+            _ => {
+                let _ = request.respond(Response::from_string("not found").with_status_code(404));
+                continue;
+            }
+        };
+        let header = Header::from_bytes("Content-Type", content_type).expect("valid header");
+        if let Err(e) = request.respond(Response::from_string(body).with_header(header)) {
+            log::warn!("could not answer a preview request: {e}");
+        }
+    }
+    Ok(())
+}
+
+struct Preview {
+    file: PathBuf,
+    diagram_type: String,
+    renderer: MdKroki,
+    runtime: tokio::runtime::Runtime,
+    /// The last rendered page and the version it was rendered at.
+    rendered: Option<(String, String)>,
+}
+
+impl Preview {
+    /// Changes whenever the modification time of the file does.
+    ///
+    /// Included files aren't watched: the file is sent to kroki unexpanded, so a change to an
+    /// include wouldn't show in the preview.
+    fn version(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        fs::metadata(&self.file)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .hash(&mut hasher);
+        format!("{:x}", hasher.finish())
+    }
+
+    /// The page with the current render, re-rendering only if the file changed.
+    fn page(&mut self) -> String {
+        let version = self.version();
+        if let Some((rendered_version, page)) = &self.rendered {
+            if *rendered_version == version {
+                return page.clone();
+            }
+        }
+
+        let rendered = fs::read_to_string(&self.file)
+            .with_context(|| format!("could not read {}", self.file.display()))
+            .and_then(|source| {
+                self.runtime
+                    .block_on(self.renderer.render_source(&self.diagram_type, source))
+            });
+        let content = match rendered {
+            Ok(html) => {
+                log::info!("rendered {}", self.file.display());
+                html
+            }
+            Err(e) => {
+                log::warn!("could not render {}: {e:#}", self.file.display());
+                format!("<pre class=\"kroki-error\">{}</pre>", escape_html(&format!("{e:#}")))
+            }
+        };
+        let page = PAGE
+            .replace("{name}", &escape_html(&self.file.display().to_string()))
+            .replace("{type}", &escape_html(&self.diagram_type))
+            .replace("{version}", &version)
+            .replace("{interval}", &POLL_INTERVAL.to_string())
+            .replace("{content}", &content);
+        self.rendered = Some((version, page.clone()));
+        page
+    }
+}
@@ -0,0 +1,201 @@
+//! Timing of the diagrams rendered by a build: a summary logged at the end of the run, and an
+//! optional JSON file for CI dashboards.
+
+use anyhow::{Context, Result};
+use boilerplate::md_kroki::FetchRecord;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of slowest diagrams listed in the summary.
+const SLOWEST: usize = 10;
+
+/// One diagram sent to kroki or read from the cache.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct DiagramTiming {
+    /// Chapter file, relative to the book's source directory.
+    pub file: Option<PathBuf>,
+    /// First line of the diagram in the chapter.
+    pub line: usize,
+    /// Kroki diagram type.
+    #[serde(rename = "type")]
+    pub diagram_type: String,
+    /// Endpoint that rendered the diagram; absent for cache hits and failures.
+    pub endpoint: Option<String>,
+    /// Whether the response came from the cache.
+    pub cached: bool,
+    /// Whether the diagram could not be rendered.
+    pub failed: bool,
+    /// Time spent getting kroki's response.
+    pub millis: u64,
+}
+
+impl DiagramTiming {
+    fn location(&self) -> String {
+        match &self.file {
+            Some(file) => format!("{}:{}", file.display(), self.line),
+            None => format!("line {}", self.line),
+        }
+    }
+}
+
+/// The statistics of a build, as written to the stats file.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct RenderStats {
+    /// Distinct diagrams rendered; a diagram shared between chapters counts once.
+    pub diagrams: usize,
+    /// Number of diagrams by type.
+    pub types: BTreeMap<String, usize>,
+    /// Diagrams read from the cache.
+    pub cache_hits: usize,
+    /// Diagrams that could not be rendered, including ones that failed before they were sent,
+    /// like a malformed tag or a missing file.
+    pub failures: usize,
+    /// Wall-clock time of rendering the whole book.
+    pub total_millis: u64,
+    /// 95th percentile of the time taken by requests to kroki; cache hits are left out.
+    pub p95_millis: Option<u64>,
+    /// Requests to kroki by endpoint.
+    pub endpoints: BTreeMap<String, usize>,
+    /// The slowest diagrams, slowest first.
+    pub slowest: Vec<DiagramTiming>,
+}
+
+/// Collects the timing of every diagram during a build.
+#[derive(Default)]
+pub struct StatsRecorder {
+    timings: Mutex<Vec<DiagramTiming>>,
+}
+
+impl StatsRecorder {
+    /// Record one fetch reported by the renderer.
+    pub fn record(&self, fetch: &FetchRecord) {
+        let timing = DiagramTiming {
+            file: fetch.document.map(Path::to_path_buf),
+            line: *fetch.spec.lines.start(),
+            diagram_type: fetch.spec.diagram_type.clone(),
+            endpoint: fetch.endpoint.map(str::to_string),
+            cached: fetch.cached,
+            failed: fetch.error.is_some(),
+            millis: fetch.elapsed.as_millis() as u64,
+        };
+        log::debug!(
+            "diagram location={} type={} endpoint={} cached={} failed={} ms={}",
+            timing.location(),
+            timing.diagram_type,
+            timing.endpoint.as_deref().unwrap_or("-"),
+            timing.cached,
+            timing.failed,
+            timing.millis
+        );
+        self.timings.lock().expect("stats lock poisoned").push(timing);
+    }
+
+    /// The statistics of everything recorded, given the time the whole render took and the number
+    /// of diagrams that failed before they could be fetched, like a `<kroki>` tag without a type.
+    pub fn finish(&self, total: Duration, unsent: usize) -> RenderStats {
+        let mut timings = self.timings.lock().expect("stats lock poisoned").clone();
+        let mut types = BTreeMap::new();
+        let mut endpoints = BTreeMap::new();
+        for timing in &timings {
+            *types.entry(timing.diagram_type.clone()).or_default() += 1;
+            if let Some(endpoint) = &timing.endpoint {
+                *endpoints.entry(endpoint.clone()).or_default() += 1;
+            }
+        }
+        let mut requests: Vec<u64> = timings
+            .iter()
+            .filter(|timing| !timing.cached)
+            .map(|timing| timing.millis)
+            .collect();
+        requests.sort_unstable();
+
+        // Ties keep chapter order, so the list is stable between runs.
+        timings.sort_by(|a, b| {
+            b.millis
+                .cmp(&a.millis)
+                .then_with(|| (&a.file, a.line).cmp(&(&b.file, b.line)))
+        });
+        RenderStats {
+            diagrams: timings.len(),
+            types,
+            cache_hits: timings.iter().filter(|timing| timing.cached).count(),
+            failures: timings.iter().filter(|timing| timing.failed).count() + unsent,
+            total_millis: total.as_millis() as u64,
+            p95_millis: percentile(&requests, 95),
+            endpoints,
+            slowest: timings.into_iter().take(SLOWEST).collect(),
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted values.
+pub(crate) fn percentile(sorted: &[u64], percent: usize) -> Option<u64> {
+    let rank = (sorted.len() * percent).div_ceil(100);
+    sorted.get(rank.max(1) - 1).copied()
+}
+
+impl RenderStats {
+    /// Log the summary at info level.
+    pub fn log(&self) {
+        if self.diagrams == 0 && self.failures == 0 {
+            return;
+        }
+        let types: Vec<_> = self
+            .types
+            .iter()
+            .map(|(diagram_type, count)| format!("{diagram_type}: {count}"))
+            .collect();
+        let types = if types.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", types.join(", "))
+        };
+        let p95 = self
+            .p95_millis
+            .map(|p95| format!(", p95 request {p95} ms"))
+            .unwrap_or_default();
+        log::info!(
+            "rendered {} diagrams{types}; {} from cache, {} failed; {} ms total{p95}",
+            self.diagrams,
+            self.cache_hits,
+            self.failures,
+            self.total_millis
+        );
+        for (endpoint, count) in &self.endpoints {
+            log::info!("  {count} requests to {endpoint}");
+        }
+        if self.slowest.is_empty() {
+            return;
+        }
+        log::info!("slowest diagrams:");
+        for timing in &self.slowest {
+            let status = match (timing.cached, timing.failed) {
+                (_, true) => " failed",
+                (true, _) => " cached",
+                _ => "",
+            };
+            log::info!(
+                "  {:>6} ms  {} ({}){status}",
+                timing.millis,
+                timing.location(),
+                timing.diagram_type
+            );
+        }
+    }
+
+    /// Write the statistics as JSON to `path`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("could not create {}", parent.display()))?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("could not write {}", path.display()))
+    }
+}
@@ -0,0 +1,35 @@
+//! Scratch directories for tests. Both the library's and the binary's tests compile this file.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A scratch directory for one test, removed when it goes out of scope, also if the test fails.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("kroki-preprocessor-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // Canonical, so paths built from it compare equal to resolved ones.
+        TempDir(dir.canonicalize().unwrap())
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Write `content` to `path` inside the directory, creating parent directories.
+    pub(crate) fn write(&self, path: &str, content: &str) -> PathBuf {
+        let path = self.0.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,393 @@
+use crate::check::{report_path, Failure, Report};
+use crate::config::KrokiConfig;
+use crate::diff_report::changed_diagrams;
+use crate::generate::{GeneratorConfig, Generators};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::paths::PathPolicy;
+use crate::stats::{percentile, StatsRecorder};
+use crate::temp_dir::TempDir;
+use boilerplate::md_kroki::{FetchRecord, MdKroki};
+use pretty_assertions::assert_eq;
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+    })
+}
+
+fn report() -> Report {
+    Report {
+        diagrams: 3,
+        failures: vec![
+            Failure {
+                file: PathBuf::from("src/intro.md"),
+                line: Some(12),
+                diagram_type: Some("plantuml".to_string()),
+                message: "400 Bad Request: syntax error".to_string(),
+            },
+            Failure {
+                file: PathBuf::from("src/setup.md"),
+                line: Some(3),
+                diagram_type: None,
+                message: "missing type tag".to_string(),
+            },
+        ],
+    }
+}
+
+#[test]
+fn check_report_as_text() {
+    assert_eq!(
+        report().to_text(),
+        "src/intro.md:12: plantuml: 400 Bad Request: syntax error\n\
+         src/setup.md:3: missing type tag\n\
+         checked 3 diagrams, 2 failed"
+    );
+}
+
+#[test]
+fn check_reports_paths_from_the_working_directory() {
+    let cwd = std::env::current_dir().unwrap();
+    assert_eq!(report_path(Path::new("./src/intro.md")), Path::new("src/intro.md"));
+    assert_eq!(report_path(Path::new("docs/./src/intro.md")), Path::new("docs/src/intro.md"));
+    assert_eq!(report_path(&cwd.join("docs/src/intro.md")), Path::new("docs/src/intro.md"));
+    let elsewhere = Path::new("/elsewhere/src/intro.md");
+    assert_eq!(report_path(elsewhere), elsewhere);
+}
+
+#[test]
+fn check_report_as_sarif() {
+    let sarif = report().to_sarif();
+    assert_eq!(sarif["version"], "2.1.0");
+    let run = &sarif["runs"][0];
+    assert_eq!(run["tool"]["driver"]["name"], "kroki-preprocessor");
+    assert_eq!(run["tool"]["driver"]["rules"][0]["id"], "kroki-render");
+    assert_eq!(
+        run["results"],
+        json!([
+            {
+                "ruleId": "kroki-render",
+                "level": "error",
+                "message": { "text": "plantuml diagram failed to render: 400 Bad Request: syntax error" },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": "src/intro.md" },
+                        "region": { "startLine": 12 }
+                    }
+                }]
+            },
+            {
+                "ruleId": "kroki-render",
+                "level": "error",
+                "message": { "text": "missing type tag" },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": "src/setup.md" },
+                        "region": { "startLine": 3 }
+                    }
+                }]
+            }
+        ])
+    );
+}
+
+#[test]
+fn path_policy_rejects_parent_escapes() {
+    let dir = TempDir::new("policy-parent");
+    dir.write("book/src/flow.puml", "inside");
+    dir.write("outside/secret.puml", "secret");
+    let book = dir.path().join("book");
+    let policy = PathPolicy::new(&book, &[], false).unwrap();
+
+    assert_eq!(policy.read(&book.join("src/../src/flow.puml")).unwrap(), "inside");
+    let error = policy
+        .read(&book.join("src/../../outside/secret.puml"))
+        .unwrap_err();
+    assert!(error.to_string().contains("is outside the book root"), "{error:#}");
+}
+
+#[cfg(unix)]
+#[test]
+fn path_policy_rejects_symlinks_out_of_the_book() {
+    let dir = TempDir::new("policy-symlink");
+    dir.write("book/src/flow.puml", "inside");
+    let secret = dir.write("outside/secret.puml", "secret");
+    let book = dir.path().join("book");
+    std::os::unix::fs::symlink(&secret, book.join("src/link.puml")).unwrap();
+    std::os::unix::fs::symlink(dir.path().join("outside"), book.join("src/shared")).unwrap();
+    std::os::unix::fs::symlink(book.join("src/flow.puml"), book.join("src/alias.puml")).unwrap();
+    let policy = PathPolicy::new(&book, &[], false).unwrap();
+
+    for link in ["src/link.puml", "src/shared/secret.puml"] {
+        let error = policy.read(&book.join(link)).unwrap_err();
+        assert!(error.to_string().contains("is outside the book root"), "{link}: {error:#}");
+    }
+    // Links that stay inside the book are fine.
+    assert_eq!(policy.read(&book.join("src/alias.puml")).unwrap(), "inside");
+}
+
+#[test]
+fn path_policy_accepts_allowed_dirs() {
+    let dir = TempDir::new("policy-allowed");
+    fs::create_dir_all(dir.path().join("book")).unwrap();
+    dir.write("shared/common.puml", "shared");
+    dir.write("other/secret.puml", "secret");
+    let book = dir.path().join("book");
+    let policy = PathPolicy::new(&book, &[PathBuf::from("../shared")], false).unwrap();
+
+    assert_eq!(policy.read(&book.join("../shared/common.puml")).unwrap(), "shared");
+    assert!(policy.read(&book.join("../other/secret.puml")).is_err());
+
+    let error = PathPolicy::new(&book, &[PathBuf::from("../missing")], false).unwrap_err();
+    assert!(error.to_string().starts_with("could not resolve allowed directory"), "{error:#}");
+}
+
+#[test]
+fn system_root_references_need_allow_system_root() {
+    let dir = TempDir::new("policy-system");
+    let inside = dir.write("book/src/flow.puml", "inside");
+    let outside = dir.write("outside/secret.puml", "secret");
+    let book = dir.path().join("book");
+    let tag = |path: &Path| format!("<kroki type=\"plantuml\" root=\"system\" path=\"{}\" />\n", path.display());
+
+    let mut config = KrokiConfig::default();
+    let renderer = config.renderer(book.clone(), PathBuf::from("src")).unwrap();
+    let error = renderer.extract(&tag(&inside)).unwrap_err();
+    assert!(error.root_cause().to_string().starts_with(r#"root="system" is disabled"#), "{error:#}");
+
+    config.allow_system_root = true;
+    let renderer = config.renderer(book.clone(), PathBuf::from("src")).unwrap();
+    let specs = renderer.extract(&tag(&inside)).unwrap();
+    assert_eq!(specs[0].source, "inside");
+    // The system root doesn't lift the directory restriction.
+    let error = renderer.extract(&tag(&outside)).unwrap_err();
+    assert!(error.root_cause().to_string().contains("is outside the book root"), "{error:#}");
+}
+
+#[test]
+fn missing_files_are_not_found_rather_than_rejected() {
+    let dir = TempDir::new("policy-missing");
+    fs::create_dir_all(dir.path().join("book/src")).unwrap();
+    let book = dir.path().join("book");
+    let policy = PathPolicy::new(&book, &[], false).unwrap();
+
+    let error = policy.read(&book.join("src/missing.puml")).unwrap_err();
+    assert!(is_not_found(&error), "{error:#}");
+    assert!(!error.to_string().contains("outside the book root"), "{error:#}");
+
+    // Which is what lets `{lang}` fall back to the default language.
+    dir.write("book/src/en/flow.puml", "english");
+    let mut config = KrokiConfig::default();
+    config.language = Some("de".to_string());
+    config.default_language = Some("en".to_string());
+    let renderer = config.renderer(book, PathBuf::from("src")).unwrap();
+    let specs = renderer
+        .for_document(Some(PathBuf::from("chapter.md")))
+        .extract("<kroki type=\"plantuml\" path=\"{lang}/flow.puml\" />\n")
+        .unwrap();
+    assert_eq!(specs[0].source, "english");
+}
+
+fn shell(script: &str, timeout: u64, inputs: &[&str]) -> GeneratorConfig {
+    GeneratorConfig {
+        command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+        cwd: None,
+        timeout,
+        inputs: inputs.iter().map(PathBuf::from).collect(),
+    }
+}
+
+fn generators(dir: &TempDir, generators: Vec<(&str, GeneratorConfig)>) -> Generators {
+    let generators = generators.into_iter().map(|(name, config)| (name.to_string(), config)).collect();
+    Generators::new(dir.path().to_path_buf(), generators, Some(dir.path().join("cache")))
+}
+
+#[test]
+fn generators_are_killed_after_their_timeout() {
+    let dir = TempDir::new("generate-timeout");
+    let generators = generators(&dir, vec![("slow", shell("sleep 5", 1, &[]))]);
+
+    let started = Instant::now();
+    let error = generators.generate("slow").unwrap_err();
+    assert!(started.elapsed() < Duration::from_secs(4), "took {:?}", started.elapsed());
+    assert_eq!(format!("{error:#}"), "generator \"slow\" failed: timed out after 1s");
+}
+
+#[test]
+fn failing_generators_report_their_stderr() {
+    let dir = TempDir::new("generate-exit");
+    let generators = generators(&dir, vec![("broken", shell("echo partial; echo 'no such table' >&2; exit 3", 5, &[]))]);
+
+    let error = generators.generate("broken").unwrap_err();
+    assert_eq!(
+        format!("{error:#}"),
+        "generator \"broken\" failed: exit status: 3: no such table"
+    );
+}
+
+#[test]
+fn unknown_generators_are_rejected() {
+    let dir = TempDir::new("generate-unknown");
+    let generators = generators(&dir, vec![("schema", shell("echo A", 5, &[]))]);
+
+    let error = generators.generate("scheme").unwrap_err().to_string();
+    assert!(error.starts_with("unknown generator \"scheme\""), "{error}");
+    assert!(!dir.path().join("cache").exists());
+}
+
+#[test]
+fn generator_outputs_are_cached_by_input_hash() {
+    let dir = TempDir::new("generate-cache");
+    dir.write("schema.sql", "create table a;");
+    let config = shell("echo run >> runs.log; cat schema.sql", 5, &["schema.sql"]);
+    let build = || generators(&dir, vec![("schema", config.clone())]);
+    let runs = || fs::read_to_string(dir.path().join("runs.log")).unwrap().lines().count();
+
+    // Within one build a generator runs once; a later build with the same inputs reads the cache.
+    let first = build();
+    assert_eq!(first.generate("schema").unwrap(), "create table a;");
+    assert_eq!(first.generate("schema").unwrap(), "create table a;");
+    assert_eq!(build().generate("schema").unwrap(), "create table a;");
+    assert_eq!(runs(), 1);
+
+    dir.write("schema.sql", "create table b;");
+    assert_eq!(build().generate("schema").unwrap(), "create table b;");
+    assert_eq!(runs(), 2);
+}
+
+#[test]
+fn slow_generators_do_not_hold_up_others() {
+    let dir = TempDir::new("generate-parallel");
+    let generators = generators(
+        &dir,
+        vec![("slow", shell("sleep 2; echo slow", 10, &[])), ("fast", shell("echo fast", 10, &[]))],
+    );
+
+    std::thread::scope(|scope| {
+        let slow = scope.spawn(|| generators.generate("slow"));
+        // Give the slow generator time to start.
+        std::thread::sleep(Duration::from_millis(300));
+        let started = Instant::now();
+        assert_eq!(generators.generate("fast").unwrap(), "fast\n");
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+        assert_eq!(slow.join().unwrap().unwrap(), "slow\n");
+    });
+}
+
+fn entry(file: &str, line: usize, source: &str, output: &str) -> ManifestEntry {
+    ManifestEntry {
+        file: Some(PathBuf::from(file)),
+        line,
+        diagram_type: "dot".to_string(),
+        source_hash: source.to_string(),
+        output_hash: output.to_string(),
+    }
+}
+
+fn changes(before: Vec<ManifestEntry>, after: Vec<ManifestEntry>) -> (usize, Vec<(usize, usize)>) {
+    let (before, after) = (Manifest { diagrams: before }, Manifest { diagrams: after });
+    let (compared, changed) = changed_diagrams(&before, &after);
+    (compared, changed.iter().map(|(old, new)| (old.line, new.line)).collect())
+}
+
+#[test]
+fn diff_report_compares_diagrams_with_the_same_source() {
+    let before = vec![
+        entry("a.md", 1, "s1", "o1"),
+        entry("a.md", 10, "s2", "o2"),
+        entry("a.md", 20, "s3", "o3"),
+        entry("b.md", 1, "s1", "o1"),
+    ];
+    let after = vec![
+        // Moved down by an edit above it, and rendered differently.
+        entry("a.md", 5, "s1", "o1-new"),
+        // Unchanged.
+        entry("a.md", 14, "s2", "o2"),
+        // Source changed: not comparable.
+        entry("a.md", 24, "s3-edited", "o3-new"),
+        // Only in the new build.
+        entry("c.md", 1, "s1", "o1-new"),
+    ];
+    // b.md's diagram is only in the old build.
+    assert_eq!(changes(before, after), (2, vec![(1, 5)]));
+}
+
+#[test]
+fn diff_report_pairs_duplicate_diagrams_in_order() {
+    let before = vec![
+        entry("a.md", 1, "s1", "o1"),
+        entry("a.md", 10, "s1", "o1"),
+        entry("a.md", 20, "s1", "o1"),
+    ];
+    let after = vec![entry("a.md", 1, "s1", "o1"), entry("a.md", 12, "s1", "o1-new")];
+    assert_eq!(changes(before, after), (2, vec![(10, 12)]));
+}
+
+#[test]
+fn percentiles_use_the_nearest_rank() {
+    assert_eq!(percentile(&[], 95), None);
+    assert_eq!(percentile(&[7], 95), Some(7));
+    assert_eq!(percentile(&[1, 2, 3, 4], 50), Some(2));
+    assert_eq!(percentile(&[1, 2, 3, 4], 95), Some(4));
+    let hundred: Vec<u64> = (1..=100).collect();
+    assert_eq!(percentile(&hundred, 95), Some(95));
+    assert_eq!(percentile(&hundred, 0), Some(1));
+}
+
+#[test]
+fn stats_summarize_fetches() {
+    // Twelve diagrams, starting on lines 1, 5, 9, ...; every third one is mermaid.
+    let content: String = (0..12)
+        .map(|n| if n % 3 == 0 { "```kroki-mermaid\ngraph TD\n```\n\n" } else { "```kroki-dot\ndigraph {}\n```\n\n" })
+        .collect();
+    let specs = MdKroki::new().extract(&content).unwrap();
+    let error = anyhow::anyhow!("400 Bad Request");
+    let recorder = StatsRecorder::default();
+    for (n, spec) in specs.iter().enumerate() {
+        // The first and last diagram take equally long, 10 ms apart from the rest.
+        let millis = match n {
+            0 | 11 => 500,
+            _ => 10 * n as u64,
+        };
+        recorder.record(&FetchRecord {
+            document: Some(Path::new("guide.md")),
+            spec,
+            endpoint: match n {
+                2 | 5 => None,
+                n if n % 2 == 0 => Some("https://a.example/"),
+                _ => Some("https://b.example/"),
+            },
+            // A slow cache hit is listed among the slowest, but left out of the request times.
+            cached: n == 2,
+            elapsed: Duration::from_millis(if n == 2 { 900 } else { millis }),
+            error: (n == 5).then_some(&error),
+        });
+    }
+
+    let nothing_sent = StatsRecorder::default().finish(Duration::from_millis(5), 3);
+    assert_eq!((nothing_sent.diagrams, nothing_sent.failures, nothing_sent.p95_millis), (0, 3, None));
+
+    let stats = recorder.finish(Duration::from_secs(3), 2);
+    assert_eq!(stats.diagrams, 12);
+    assert_eq!(stats.types, [("dot".to_string(), 8), ("mermaid".to_string(), 4)].into());
+    assert_eq!(stats.cache_hits, 1);
+    assert_eq!(stats.failures, 3, "one failed fetch and two diagrams that were never sent");
+    assert_eq!(stats.total_millis, 3000);
+    // Requests, sorted: 10, 30, 40, ..., 100, 500, 500; the 95th percentile is the 11th of 11.
+    assert_eq!(stats.p95_millis, Some(500));
+    assert_eq!(
+        stats.endpoints,
+        [("https://a.example/".to_string(), 5), ("https://b.example/".to_string(), 5)].into()
+    );
+    let slowest: Vec<_> = stats.slowest.iter().map(|timing| (timing.line, timing.millis)).collect();
+    assert_eq!(
+        slowest,
+        [(9, 900), (1, 500), (45, 500), (41, 100), (37, 90), (33, 80), (29, 70), (25, 60), (21, 50), (17, 40)]
+    );
+}